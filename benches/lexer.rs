@@ -0,0 +1,76 @@
+//! Lexes generated programs of growing size and reports the throughput,
+//! which should stay flat if lexing is linear in the size of the source.
+//!
+//! Run with `cargo bench --bench lexer`.
+
+// The modules are shared with the compiler, which uses more of them,
+// and their tests aren't built here.
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/common.rs"]
+mod common;
+#[path = "../src/diagnostic.rs"]
+mod diagnostic;
+#[path = "../src/lexer.rs"]
+mod lexer;
+#[path = "../src/source.rs"]
+mod source;
+
+use std::{hint::black_box, time::Instant};
+
+use lexer::{CommentStyle, Lexer};
+
+const SIZES_MB: [usize; 4] = [1, 4, 16, 64];
+
+/// Builds about `size` bytes of program, in the shape of machine-generated code:
+/// long runs, nested loops, and a comment on every line.
+fn generate(size: usize) -> Vec<u8> {
+    let line: &[u8] =
+        b"++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++. # hello\n";
+
+    line.iter().copied().cycle().take(size).collect()
+}
+
+fn bench(name: &str, size: usize, lex: impl Fn(&[u8]) -> usize) {
+    let text = generate(size);
+
+    // Take the best of a few runs, to keep noise from other processes out.
+    let mut best = f64::MAX;
+    let mut tokens = 0;
+
+    for _ in 0..5 {
+        let start = Instant::now();
+        tokens = black_box(lex(black_box(&text)));
+        best = best.min(start.elapsed().as_secs_f64());
+    }
+
+    println!(
+        "{:<10} {:>4} MB {:>10} tokens {:>9.2} ms {:>8.1} MB/s",
+        name,
+        size >> 20,
+        tokens,
+        best * 1000.0,
+        (size >> 20) as f64 / best,
+    );
+}
+
+fn main() {
+    for mb in SIZES_MB {
+        bench("plain", mb << 20, |text| Lexer::new(text, 0).lex().len());
+    }
+
+    for mb in SIZES_MB {
+        bench("comments", mb << 20, |text| {
+            Lexer::new(text, 0)
+                .with_comments(&[CommentStyle::Hash])
+                .lex()
+                .len()
+        });
+    }
+
+    for mb in SIZES_MB {
+        bench("strict", mb << 20, |text| {
+            Lexer::new(text, 0).with_strict(true).lex().len()
+        });
+    }
+}
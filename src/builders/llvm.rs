@@ -23,7 +23,12 @@ pub fn builder_llvm(
     let exe_file = build_dir.join(EXE_FILE);
 
     let mut link_cmd = Command::new("clang");
-    link_cmd.arg(asm_file).arg("-o").arg(&exe_file);
+    // Brainfork programs run on threads.
+    link_cmd
+        .arg(asm_file)
+        .arg("-pthread")
+        .arg("-o")
+        .arg(&exe_file);
 
     let _linker_output = link_cmd.spawn()?.wait_with_output()?;
 
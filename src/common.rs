@@ -3,21 +3,99 @@ use std::{
     fmt::{self, Debug, Display},
 };
 
+use crate::diagnostic::Diagnostic;
+
+/// Identifies the source file a span points into.
+pub type FileId = u32;
+
+/// A region of a source file.
+/// `start` and `end` are byte offsets into the file (`end` is exclusive),
+/// `ln` and `col` are the 1-based line and column of `start`.
+/// Every token carries one, so the fields are 32 bits to keep tokens small;
+/// `SourceMap::add` rejects files too large for that.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub file: FileId,
+    pub start: u32,
+    pub end: u32,
+    pub ln: u32,
+    pub col: u32,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize, ln: usize, col: usize) -> Span {
+        let narrow = |n: usize| u32::try_from(n).expect("source files are smaller than 4 GiB");
+
+        Span {
+            file,
+            start: narrow(start),
+            end: narrow(end),
+            ln: narrow(ln),
+            col: narrow(col),
+        }
+    }
+
+    /// Returns a span that starts where `self` starts and ends where `other` ends.
+    /// Both have to be in the same file, with `other` not before `self`.
+    pub fn to(&self, other: Span) -> Span {
+        debug_assert!(
+            self.file == other.file && self.start <= other.start,
+            "can't join {:?} to {:?}",
+            self,
+            other
+        );
+
+        Span {
+            end: other.end,
+            ..*self
+        }
+    }
+
+    /// Like `to`, but returns `self` when `other` is in another file or starts before it,
+    /// which happens when the code between them comes from macros or included files.
+    pub fn join(&self, other: Span) -> Span {
+        if self.file == other.file && self.start <= other.start {
+            self.to(other)
+        } else {
+            *self
+        }
+    }
+}
+
+/// One or more diagnostics that stopped compilation.
 pub struct XBFError {
-    msg: String,
+    diagnostics: Vec<Diagnostic>,
 }
 
-impl From<&str> for XBFError {
-    fn from(value: &str) -> Self {
+impl XBFError {
+    pub fn get_diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl From<Diagnostic> for XBFError {
+    fn from(value: Diagnostic) -> Self {
         XBFError {
-            msg: value.to_string(),
+            diagnostics: vec![value],
         }
     }
 }
 
+impl From<Vec<Diagnostic>> for XBFError {
+    fn from(value: Vec<Diagnostic>) -> Self {
+        XBFError { diagnostics: value }
+    }
+}
+
+impl From<&str> for XBFError {
+    fn from(value: &str) -> Self {
+        XBFError::from(Diagnostic::error(value))
+    }
+}
+
 impl From<String> for XBFError {
     fn from(value: String) -> Self {
-        XBFError { msg: value }
+        XBFError::from(value.as_str())
     }
 }
 
@@ -29,7 +107,8 @@ impl From<fmt::Error> for XBFError {
 
 impl Debug for XBFError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
+        let msgs: Vec<&str> = self.diagnostics.iter().map(|d| d.get_msg()).collect();
+        write!(f, "{}", msgs.join("\n"))
     }
 }
 
@@ -45,10 +124,17 @@ impl Error for XBFError {
     }
 }
 
-pub fn try_index<T>(vec: &Vec<T>, index: usize) -> Option<&T> {
-    if index < vec.len() {
-        Some(&vec[index])
-    } else {
-        None
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_stays_in_one_file() {
+        let (a, b) = (Span::new(0, 2, 3, 1, 3), Span::new(0, 10, 11, 2, 4));
+
+        assert_eq!(a.join(b), Span::new(0, 2, 11, 1, 3));
+        // Macros can put code from earlier in the file after later code.
+        assert_eq!(b.join(a), b);
+        assert_eq!(a.join(Span::new(1, 10, 11, 2, 4)), a);
     }
 }
@@ -0,0 +1,310 @@
+use std::fmt::{self, Write};
+
+use crate::{
+    common::{FileId, Span},
+    source::SourceMap,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            Level::Error => RED,
+            Level::Warning => YELLOW,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Label {
+    span: Span,
+    msg: String,
+    primary: bool,
+}
+
+/// A message about the source, optionally pointing at the spans it is about.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    level: Level,
+    msg: String,
+    labels: Vec<Label>,
+    notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, msg: &str) -> Diagnostic {
+        Diagnostic {
+            level,
+            msg: msg.to_string(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(msg: &str) -> Diagnostic {
+        Diagnostic::new(Level::Error, msg)
+    }
+
+    pub fn warning(msg: &str) -> Diagnostic {
+        Diagnostic::new(Level::Warning, msg)
+    }
+
+    /// Points the diagnostic at the span that caused it, underlined with `^`.
+    pub fn with_span(mut self, span: Span, label: &str) -> Diagnostic {
+        self.labels.push(Label {
+            span,
+            msg: label.to_string(),
+            primary: true,
+        });
+        self
+    }
+
+    /// Adds a span that gives context to the diagnostic, underlined with `-`.
+    pub fn with_label(mut self, span: Span, label: &str) -> Diagnostic {
+        self.labels.push(Label {
+            span,
+            msg: label.to_string(),
+            primary: false,
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Diagnostic {
+        self.notes.push(note.to_string());
+        self
+    }
+
+    pub fn get_msg(&self) -> &str {
+        &self.msg
+    }
+}
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Makes a source line safe to print, keeping one character per column:
+/// tabs become spaces and other control bytes become a replacement character.
+fn printable(line: &str) -> String {
+    line.trim_end()
+        .chars()
+        .map(|c| match c {
+            '\t' => ' ',
+            c if c.is_control() => '\u{fffd}',
+            c => c,
+        })
+        .collect()
+}
+
+/// Renders diagnostics in the style of rustc:
+///
+/// ```text
+/// error: unmatched ']'
+///  --> hello.bf:3:5
+///   |
+/// 3 | +++]
+///   |    ^ no loop to close
+/// ```
+pub struct Renderer<'a> {
+    sources: &'a SourceMap,
+    color: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(sources: &'a SourceMap, color: bool) -> Renderer<'a> {
+        Renderer { sources, color }
+    }
+
+    fn paint(&self, out: &mut String, style: &str, text: &str) -> fmt::Result {
+        if self.color {
+            write!(out, "{}{}{}", style, text, RESET)
+        } else {
+            out.write_str(text)
+        }
+    }
+
+    pub fn render(&self, diag: &Diagnostic) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+
+        self.paint(&mut out, diag.level.color(), diag.level.name())?;
+        self.paint(&mut out, BOLD, &format!(": {}", diag.msg))?;
+        out.push('\n');
+
+        let width = diag
+            .labels
+            .iter()
+            .map(|l| l.span.ln.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pad = " ".repeat(width);
+
+        // Labels are grouped by file, keeping the file of the primary label first,
+        // and shown in line order within each file.
+        let mut files: Vec<FileId> = Vec::new();
+        for l in diag.labels.iter().filter(|l| l.primary).chain(&diag.labels) {
+            if !files.contains(&l.span.file) {
+                files.push(l.span.file);
+            }
+        }
+
+        for (i, file) in files.iter().enumerate() {
+            let mut labels: Vec<&Label> = diag
+                .labels
+                .iter()
+                .filter(|l| l.span.file == *file)
+                .collect();
+            labels.sort_by_key(|l| (l.span.ln, l.span.col));
+
+            let first = diag
+                .labels
+                .iter()
+                .find(|l| l.primary && l.span.file == *file)
+                .unwrap_or(labels[0]);
+
+            self.paint(
+                &mut out,
+                BLUE,
+                &format!("{}{} ", pad, if i == 0 { "-->" } else { ":::" }),
+            )?;
+            writeln!(out, "{}", self.sources.location(first.span))?;
+            self.paint(&mut out, BLUE, &format!("{} |", pad))?;
+            out.push('\n');
+
+            let mut last_ln = 0;
+
+            for l in labels {
+                let source = self.sources.get(*file);
+                let ln = l.span.ln as usize;
+
+                // A single skipped line is shown as-is, longer gaps are elided.
+                if last_ln != 0 && ln == last_ln + 2 {
+                    self.paint(&mut out, BLUE, &format!("{:>width$} | ", last_ln + 1))?;
+                    writeln!(out, "{}", printable(&source.get_line(last_ln + 1)))?;
+                } else if last_ln != 0 && ln > last_ln + 2 {
+                    self.paint(&mut out, BLUE, "...")?;
+                    out.push('\n');
+                }
+
+                if ln != last_ln {
+                    self.paint(&mut out, BLUE, &format!("{:>width$} | ", ln))?;
+                    writeln!(out, "{}", printable(&source.get_line(ln)))?;
+                }
+                last_ln = ln;
+
+                // The underline stops at the end of the line the span starts on.
+                let bytes = source
+                    .get_text()
+                    .get(l.span.start as usize..l.span.end as usize)
+                    .unwrap_or(&[]);
+                let len = source
+                    .decode(bytes)
+                    .split('\n')
+                    .next()
+                    .unwrap_or("")
+                    .chars()
+                    .count()
+                    .max(1);
+
+                let (mark, style) = if l.primary {
+                    ("^", diag.level.color())
+                } else {
+                    ("-", BLUE)
+                };
+
+                self.paint(&mut out, BLUE, &format!("{} | ", pad))?;
+                out.push_str(&" ".repeat(l.span.col as usize - 1));
+                self.paint(
+                    &mut out,
+                    style,
+                    format!("{} {}", mark.repeat(len), l.msg).trim_end(),
+                )?;
+                out.push('\n');
+            }
+        }
+
+        let mut notes = diag.notes.clone();
+
+        // Show how the file the diagnostic points into was included.
+        if let Some(l) = diag.labels.iter().find(|l| l.primary) {
+            let mut from = self.sources.get(l.span.file).get_included_from();
+
+            while let Some(span) = from {
+                notes.push(format!("included from {}", self.sources.location(span)));
+                from = self.sources.get(span.file).get_included_from();
+            }
+        }
+
+        if !diag.labels.is_empty() && !notes.is_empty() {
+            self.paint(&mut out, BLUE, &format!("{} |", pad))?;
+            out.push('\n');
+        }
+
+        for note in &notes {
+            self.paint(&mut out, BLUE, &format!("{} = ", pad))?;
+            self.paint(&mut out, BOLD, "note")?;
+            writeln!(out, ": {}", note)?;
+        }
+
+        Ok(out)
+    }
+
+    /// Renders the closing line that counts the errors and warnings emitted.
+    pub fn summary(&self, diags: &[Diagnostic]) -> Result<String, fmt::Error> {
+        let errors = diags.iter().filter(|d| d.level == Level::Error).count();
+        let warnings = diags.iter().filter(|d| d.level == Level::Warning).count();
+
+        let plural = |n: usize, word: &str| {
+            if n == 1 {
+                format!("{} {}", n, word)
+            } else {
+                format!("{} {}s", n, word)
+            }
+        };
+
+        let mut out = String::new();
+
+        if errors > 0 {
+            self.paint(&mut out, RED, "error")?;
+            self.paint(
+                &mut out,
+                BOLD,
+                &format!(": aborting due to {}", plural(errors, "previous error")),
+            )?;
+
+            if warnings > 0 {
+                self.paint(
+                    &mut out,
+                    BOLD,
+                    &format!("; {} emitted", plural(warnings, "warning")),
+                )?;
+            }
+        } else if warnings > 0 {
+            self.paint(&mut out, YELLOW, "warning")?;
+            self.paint(
+                &mut out,
+                BOLD,
+                &format!(": {} emitted", plural(warnings, "warning")),
+            )?;
+        }
+
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        Ok(out)
+    }
+}
@@ -0,0 +1,273 @@
+use crate::lexer::{Token, TokenType};
+
+/// A piece of a source file, in the order the formatter lays them out.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Item<'a> {
+    /// Commands that don't open or close a block.
+    Code(&'a [u8]),
+    Open(&'a [u8]),
+    Close(&'a [u8]),
+    /// A comment on the same line as the code before it.
+    Trailing(&'a [u8]),
+    /// A comment on a line of its own.
+    Comment(&'a [u8]),
+    /// One or more blank lines.
+    Blank,
+}
+
+fn trim(mut text: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = text {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        text = rest;
+    }
+
+    while let [rest @ .., last] = text {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        text = rest;
+    }
+
+    text
+}
+
+/// Splits the text between two tokens into comments and blank lines.
+/// Line breaks between commands alone don't matter, so code can be rewrapped.
+fn gap_items<'a>(gap: &'a [u8], after_code: bool, items: &mut Vec<Item<'a>>) {
+    let lines: Vec<&[u8]> = gap.split(|ch| *ch == b'\n').collect();
+
+    for (i, line) in lines.iter().enumerate() {
+        let line = trim(line);
+
+        if !line.is_empty() {
+            items.push(if i == 0 && after_code {
+                Item::Trailing(line)
+            } else {
+                Item::Comment(line)
+            });
+        } else if i > 0 && i < lines.len() - 1 && items.last() != Some(&Item::Blank) {
+            items.push(Item::Blank);
+        }
+    }
+}
+
+/// Rewrites source into a canonical layout:
+/// blocks that hold other blocks or comments are split over lines and indented,
+/// other code is wrapped at the line width, and comments stay next to the code around them.
+pub struct Formatter {
+    width: usize,
+    indent: usize,
+}
+
+impl Formatter {
+    pub fn new(width: usize, indent: usize) -> Formatter {
+        Formatter { width, indent }
+    }
+
+    /// How much code fits on a line at nesting `level`. At least one command always fits.
+    fn avail(&self, level: usize) -> usize {
+        self.width.saturating_sub(level * self.indent).max(1)
+    }
+
+    /// Formats `text`, given the tokens the lexer found in it.
+    pub fn format(&self, text: &[u8], tokens: &[Token]) -> Vec<u8> {
+        let mut items: Vec<Item> = Vec::new();
+        let mut end = 0;
+
+        for t in tokens {
+            let span = t.get_span();
+            let (start, stop) = (span.start as usize, span.end as usize);
+
+            gap_items(&text[end..start], end > 0, &mut items);
+
+            let code = &text[start..stop];
+
+            items.push(match t.get_typ() {
+                TokenType::BracketOpen | TokenType::ProcOpen => Item::Open(code),
+                TokenType::BracketClose | TokenType::ProcClose => Item::Close(code),
+                _ => Item::Code(code),
+            });
+
+            end = stop;
+        }
+
+        gap_items(&text[end..], end > 0, &mut items);
+
+        while items.last() == Some(&Item::Blank) {
+            items.pop();
+        }
+
+        // Each line is its nesting level and its text.
+        let mut lines: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut line: Vec<u8> = Vec::new();
+        let mut level = 0;
+
+        let flush = |lines: &mut Vec<(usize, Vec<u8>)>, line: &mut Vec<u8>, level: usize| {
+            if !line.is_empty() {
+                lines.push((level, std::mem::take(line)));
+            }
+        };
+
+        let mut i = 0;
+
+        while i < items.len() {
+            match items[i] {
+                Item::Code(code) => {
+                    for ch in code {
+                        if line.len() + 1 > self.avail(level) {
+                            flush(&mut lines, &mut line, level);
+                        }
+                        line.push(*ch);
+                    }
+                }
+                Item::Open(open) => {
+                    // A block of plain code that fits on a line stays inline, like '[->+<]'.
+                    let inline = items[i + 1..]
+                        .iter()
+                        .position(|item| !matches!(item, Item::Code(_)))
+                        .map(|j| i + 1 + j)
+                        .filter(|j| matches!(items[*j], Item::Close(_)));
+
+                    if let Some(j) = inline {
+                        let mut block = open.to_vec();
+                        for item in &items[i + 1..=j] {
+                            if let Item::Code(code) | Item::Close(code) = item {
+                                block.extend_from_slice(code);
+                            }
+                        }
+
+                        if block.len() <= self.avail(level) {
+                            if line.len() + block.len() > self.avail(level) {
+                                flush(&mut lines, &mut line, level);
+                            }
+                            line.extend_from_slice(&block);
+
+                            i = j + 1;
+                            continue;
+                        }
+                    }
+
+                    flush(&mut lines, &mut line, level);
+                    lines.push((level, open.to_vec()));
+                    level += 1;
+                }
+                Item::Close(close) => {
+                    flush(&mut lines, &mut line, level);
+                    level = level.saturating_sub(1);
+                    lines.push((level, close.to_vec()));
+                }
+                Item::Trailing(comment) => {
+                    // After a bracket, the comment goes on the bracket's line.
+                    let (at, mut text) = match lines.pop() {
+                        Some(last) if line.is_empty() => last,
+                        last => {
+                            lines.extend(last);
+                            (level, std::mem::take(&mut line))
+                        }
+                    };
+
+                    if !text.is_empty() {
+                        text.push(b' ');
+                    }
+                    text.extend_from_slice(comment);
+
+                    lines.push((at, text));
+                }
+                Item::Comment(comment) => {
+                    flush(&mut lines, &mut line, level);
+                    lines.push((level, comment.to_vec()));
+                }
+                Item::Blank => {
+                    flush(&mut lines, &mut line, level);
+
+                    if !lines.is_empty() {
+                        lines.push((0, Vec::new()));
+                    }
+                }
+            }
+
+            i += 1;
+        }
+
+        flush(&mut lines, &mut line, level);
+
+        let mut out = Vec::new();
+
+        for (level, line) in lines {
+            if !line.is_empty() {
+                out.extend(std::iter::repeat_n(b' ', level * self.indent));
+            }
+            out.extend_from_slice(&line);
+            out.push(b'\n');
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+
+    fn format_with(formatter: &Formatter, code: &str) -> String {
+        let tokens = Lexer::new(code.as_bytes(), 0).lex();
+        String::from_utf8(formatter.format(code.as_bytes(), &tokens)).unwrap()
+    }
+
+    fn format(code: &str) -> String {
+        format_with(&Formatter::new(80, 4), code)
+    }
+
+    const SAMPLES: [&str; 5] = [
+        include_str!("../examples/hello.bf"),
+        include_str!("../examples/adder.bf"),
+        "+[->+<] add\n\n\n>>[-[<+>-] inner\n]<",
+        "[-]>[a[b]c]",
+        "  read a line\n,[\n.,  echo\n\n]   done  \n\n",
+    ];
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for width in [80, 12, 1] {
+            let formatter = Formatter::new(width, 4);
+
+            for code in SAMPLES {
+                let once = format_with(&formatter, code);
+                assert_eq!(format_with(&formatter, &once), once, "{:?}", code);
+            }
+        }
+    }
+
+    #[test]
+    fn comments_stay_with_their_code() {
+        assert_eq!(
+            format("+[->+<] add\n\n\n>>[-[<+>-] inner\n]<"),
+            "+[->+<] add\n\n>>\n[\n    -[<+>-] inner\n]\n<\n"
+        );
+        assert_eq!(format("[a[b]c]"), "[ a\n    [ b\n    ] c\n]\n");
+        // A comment on its own line stays before the code after it.
+        assert_eq!(
+            format("  read a line\n,[\n.,  echo\n\n]   done  \n"),
+            "read a line\n,\n[\n    ., echo\n\n] done\n"
+        );
+    }
+
+    #[test]
+    fn code_wraps_at_the_width() {
+        let formatter = Formatter::new(10, 2);
+        let code = format!("{}[>{}<-]", "+".repeat(25), "+".repeat(30));
+        let formatted = format_with(&formatter, &code);
+
+        for line in formatted.lines() {
+            assert!(line.len() <= 10, "{:?} is too long", line);
+        }
+        assert!(formatted.starts_with("++++++++++\n"), "{}", formatted);
+        assert!(formatted.contains("\n  >+++++++\n"), "{}", formatted);
+
+        // Blocks that fit stay on one line.
+        assert_eq!(format_with(&formatter, "[->+<]"), "[->+<]\n");
+    }
+}
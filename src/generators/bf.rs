@@ -0,0 +1,176 @@
+use std::error::Error;
+
+use crate::ir::Op;
+
+fn repeat(out: &mut Vec<u8>, ch: u8, n: usize) {
+    out.extend(std::iter::repeat_n(ch, n));
+}
+
+/// Appends the moves to get to `offset` from the pointer, or back from it.
+fn walk(out: &mut Vec<u8>, offset: isize) {
+    if offset < 0 {
+        repeat(out, b'<', offset.unsigned_abs());
+    } else {
+        repeat(out, b'>', offset as usize);
+    }
+}
+
+fn emit(ops: &[Op], out: &mut Vec<u8>) {
+    for op in ops {
+        match op {
+            // Cells wrap at 256, so additions are spelled the shortest way.
+            Op::Add(offset, n) => {
+                walk(out, *offset);
+
+                if *n <= 128 {
+                    repeat(out, b'+', *n as usize);
+                } else {
+                    repeat(out, b'-', 256 - *n as usize);
+                }
+
+                walk(out, -offset);
+            }
+            Op::Move(n) => walk(out, *n),
+            // Only the passes of `simplify` run, which keep the loops these come from.
+            Op::Set(..) | Op::MulAdd(..) | Op::If(_) | Op::Scan(_) => {
+                unreachable!("{:?} isn't made by simplifying", op)
+            }
+            Op::Loop(body) => {
+                out.push(b'[');
+                emit(body, out);
+                out.push(b']');
+            }
+            Op::Procedure(body) => {
+                out.push(b'(');
+                emit(body, out);
+                out.push(b')');
+            }
+            Op::Call => out.push(b':'),
+            Op::Fork(_) => out.push(b'Y'),
+            Op::Debug => out.push(b'#'),
+            Op::Output => out.push(b'.'),
+            Op::Input => out.push(b','),
+            Op::End => out.push(b'@'),
+            Op::Store => out.push(b'$'),
+            Op::Fetch => out.push(b'!'),
+            Op::ShiftRight => out.push(b'}'),
+            Op::ShiftLeft => out.push(b'{'),
+            Op::Not => out.push(b'~'),
+            Op::Xor => out.push(b'^'),
+            Op::And => out.push(b'&'),
+            Op::Or => out.push(b'|'),
+        }
+    }
+}
+
+/// Emits the program as minimal source, without comments or code that does nothing.
+/// It should be simplified first.
+/// Embedded input follows the program after a '!'.
+pub fn generator_bf(program: Vec<Op>, input: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut result = Vec::new();
+
+    emit(&program, &mut result);
+
+    if !input.is_empty() {
+        result.push(b'!');
+        result.extend_from_slice(input);
+    } else {
+        result.push(b'\n');
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ir::{self, passes},
+        lexer::Lexer,
+        parser::Parser,
+        source::SourceMap,
+    };
+
+    fn generate(code: &str) -> Vec<u8> {
+        let mut sources = SourceMap::new();
+        let id = sources.add("<test>", code.as_bytes().to_vec()).unwrap();
+        let tokens = Lexer::new(sources.get(id).get_text(), id).lex();
+
+        match Parser::new(tokens, &sources).parse() {
+            Ok(program) => generator_bf(passes::simplify(ir::lower(&program)), &[]).unwrap(),
+            Err(_) => panic!("{} doesn't parse", code),
+        }
+    }
+
+    /// Runs plain Brainfuck with wrapping cells, returning what it prints.
+    fn run(code: &[u8], input: &[u8]) -> Vec<u8> {
+        let (mut tape, mut ptr, mut pc) = (vec![0u8; 1000], 0, 0);
+        let (mut input, mut out) = (input.iter(), Vec::new());
+
+        while pc < code.len() {
+            match code[pc] {
+                b'+' => tape[ptr] = tape[ptr].wrapping_add(1),
+                b'-' => tape[ptr] = tape[ptr].wrapping_sub(1),
+                b'>' => ptr += 1,
+                b'<' => ptr -= 1,
+                b'.' => out.push(tape[ptr]),
+                b',' => tape[ptr] = input.next().copied().unwrap_or(0),
+                b'[' if tape[ptr] == 0 => {
+                    let mut depth = 1;
+                    while depth > 0 {
+                        pc += 1;
+                        depth += match code[pc] {
+                            b'[' => 1,
+                            b']' => -1,
+                            _ => 0,
+                        };
+                    }
+                }
+                b']' if tape[ptr] != 0 => {
+                    let mut depth = 1;
+                    while depth > 0 {
+                        pc -= 1;
+                        depth += match code[pc] {
+                            b']' => 1,
+                            b'[' => -1,
+                            _ => 0,
+                        };
+                    }
+                }
+                _ => (),
+            }
+            pc += 1;
+        }
+
+        out
+    }
+
+    #[test]
+    fn round_trip_keeps_behaviour() {
+        let programs = [
+            "[a comment loop.] ++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.+++++++..+++.",
+            "+++>><<-- +-+- >+<- [.-]",
+            ",[.,] echo until the input runs out",
+            "++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ ++++ . wrap: ---- .",
+            "+[-]++[-][-]>[-]+++.",
+        ];
+
+        for code in programs {
+            let out = generate(code);
+
+            assert_eq!(
+                run(&out, b"hi"),
+                run(code.as_bytes(), b"hi"),
+                "{} became {}",
+                code,
+                String::from_utf8_lossy(&out)
+            );
+        }
+    }
+
+    #[test]
+    fn removes_what_does_nothing() {
+        assert_eq!(generate("[comment] +-<> ++[-][-]"), b"++[-]\n");
+        assert_eq!(generate(&"+".repeat(255)), b"-\n");
+    }
+}
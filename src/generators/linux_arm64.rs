@@ -1,70 +1,577 @@
-use std::{error::Error, fmt::write};
+use std::{
+    error::Error,
+    fmt::{self, write},
+};
 
 use crate::{
-    generators::common::match_brackets,
-    lexer::{Token, TokenType},
+    common::{Span, XBFError},
+    diagnostic::Diagnostic,
+    ir::Op,
 };
 
+/// How many cells the debug command prints.
+const DEBUG_CELLS: usize = 16;
+
+/// The error for a scan that finds no zero cell before the edge of the tape.
+const OFF_TAPE: &str = "error: a scan for a zero cell ran off the tape";
+
+/// Returns the code to put `value` into `reg` a halfword at a time,
+/// since `mov` only takes some immediates.
+fn load(reg: &str, value: i64) -> String {
+    let value = value as u64;
+    let mut result = format!("movz {}, {}\n", reg, value & 0xffff);
+
+    for shift in [16, 32, 48] {
+        let part = (value >> shift) & 0xffff;
+
+        if part != 0 {
+            result.push_str(&format!("movk {}, {}, lsl {}\n", reg, part, shift));
+        }
+    }
+
+    result
+}
+
+/// Returns the routines behind the debug command.
+/// `_debug` writes the pointer and a row of cells holding it to stderr, like
+/// `pointer 18, cells from 16: 0 0 72 101 ...`, using `_debug_str` to copy a string
+/// and `_debug_num` to write a number into the buffer at x4.
+fn debug_routines(memory_size: usize) -> String {
+    let count = DEBUG_CELLS.min(memory_size);
+
+    format!(
+        "_debug:
+stp x0, x1, [sp, -32]!
+stp x30, x16, [sp, 16]
+adrp x2, mem@PAGE
+add x2, x2, mem@PAGEOFF
+sub x10, x1, x2
+and x11, x10, 0xfffffffffffffff0
+{}cmp x11, x12
+csel x11, x11, x12, lo
+add x13, x2, x11
+adrp x4, debug_buf@PAGE
+add x4, x4, debug_buf@PAGEOFF
+adrp x5, debug_pointer@PAGE
+add x5, x5, debug_pointer@PAGEOFF
+bl _debug_str
+mov x3, x10
+bl _debug_num
+adrp x5, debug_cells@PAGE
+add x5, x5, debug_cells@PAGEOFF
+bl _debug_str
+mov x3, x11
+bl _debug_num
+mov w6, 58
+strb w6, [x4], 1
+mov x14, {}
+1:
+mov w6, 32
+strb w6, [x4], 1
+ldrb w3, [x13], 1
+bl _debug_num
+subs x14, x14, 1
+b.ne 1b
+mov w6, 10
+strb w6, [x4], 1
+adrp x1, debug_buf@PAGE
+add x1, x1, debug_buf@PAGEOFF
+sub x2, x4, x1
+mov x0, 2
+mov x16, 4
+svc 0x80
+ldp x30, x16, [sp, 16]
+ldp x0, x1, [sp], 32
+ret
+_debug_str:
+ldrb w6, [x5], 1
+cbz w6, 1f
+strb w6, [x4], 1
+b _debug_str
+1:
+ret
+_debug_num:
+mov x5, x3
+mov x6, 0
+mov x7, 10
+1:
+udiv x5, x5, x7
+add x6, x6, 1
+cbnz x5, 1b
+add x4, x4, x6
+mov x5, x4
+2:
+udiv x8, x3, x7
+msub x9, x8, x7, x3
+add w9, w9, 48
+strb w9, [x5, -1]!
+mov x3, x8
+cbnz x3, 2b
+ret
+",
+        load("x12", (memory_size - count) as i64),
+        count
+    )
+}
+
+/// Returns the code for a scan, which moves x1 by `stride` until it reaches a zero cell.
+/// Strides of one look at a word at a time from the first 8-byte boundary,
+/// using that `(x - 0x01...01) & !x & 0x80...80` is nonzero only if a byte of x is zero.
+/// A scan that runs off the tape ends the program with an error from `_off_tape`.
+fn scan(stride: isize, memory_size: usize) -> String {
+    let mut result = format!(
+        "cbz w0, 9f
+strb w0, [x1]
+adrp x9, mem@PAGE
+add x9, x9, mem@PAGEOFF
+{}add x10, x9, x10
+",
+        load("x10", memory_size as i64)
+    );
+
+    // x9 is the start of the tape and x10 is its end.
+    match stride {
+        1 => result.push_str(
+            "1:
+tst x1, 7
+b.eq 2f
+cmp x1, x10
+b.hs 7f
+ldrb w11, [x1]
+cbz w11, 8f
+add x1, x1, 1
+b 1b
+2:
+add x11, x1, 8
+cmp x11, x10
+b.hi 3f
+ldr x12, [x1]
+mov x13, 0x0101010101010101
+sub x13, x12, x13
+bic x13, x13, x12
+tst x13, 0x8080808080808080
+b.ne 3f
+mov x1, x11
+b 2b
+3:
+cmp x1, x10
+b.hs 7f
+ldrb w11, [x1]
+cbz w11, 8f
+add x1, x1, 1
+b 3b
+",
+        ),
+        -1 => result.push_str(
+            "1:
+and x11, x1, 7
+cmp x11, 7
+b.eq 2f
+cmp x1, x9
+b.lo 7f
+ldrb w11, [x1]
+cbz w11, 8f
+sub x1, x1, 1
+b 1b
+2:
+sub x11, x1, 7
+cmp x11, x9
+b.lo 3f
+ldr x12, [x11]
+mov x13, 0x0101010101010101
+sub x13, x12, x13
+bic x13, x13, x12
+tst x13, 0x8080808080808080
+b.ne 3f
+sub x1, x1, 8
+b 2b
+3:
+cmp x1, x9
+b.lo 7f
+ldrb w11, [x1]
+cbz w11, 8f
+sub x1, x1, 1
+b 3b
+",
+        ),
+        _ => result.push_str(&format!(
+            "{}1:
+add x1, x1, x12
+cmp x1, x9
+b.lo 7f
+cmp x1, x10
+b.hs 7f
+ldrb w11, [x1]
+cbnz w11, 1b
+",
+            load("x12", stride as i64)
+        )),
+    }
+
+    result.push_str("8:\nmov w0, 0\nb 9f\n7:\nb _off_tape\n9:\n");
+
+    result
+}
+
+/// Finds the first Brainfork fork in the program, which this target can't run.
+fn find_fork(ops: &[Op]) -> Option<Span> {
+    ops.iter().find_map(|op| match op {
+        Op::Fork(span) => Some(*span),
+        Op::Loop(body) | Op::Procedure(body) => find_fork(body),
+        _ => None,
+    })
+}
+
+/// State shared while generating the assembly for the program.
+struct Generator {
+    /// The routines for pbrain procedures, which go after the main program.
+    procedures: String,
+    loops: usize,
+    procs: usize,
+    /// The length of the input embedded in the program.
+    input_len: usize,
+    /// Whether the debug command was used, so its routines are needed.
+    debug: bool,
+    /// Whether a scan was used, so the routine for running off the tape is needed.
+    scan: bool,
+    memory_size: usize,
+}
+
+impl Generator {
+    /// Appends the code for `ops` to `result`.
+    fn generate_ops(&mut self, ops: &[Op], result: &mut String) -> fmt::Result {
+        for op in ops {
+            match op {
+                // Taking one away is adding 255, so w0 is masked to keep it to a byte
+                // for the zero checks of loops.
+                Op::Add(0, n) => write(result, format_args!("add w0, w0, {}\nand w0, w0, 0xff\n", n)),
+                // Other cells are only in memory, at x9.
+                Op::Add(offset, n) => write(
+                    result,
+                    format_args!(
+                        "{}add x9, x1, x10\nldrb w10, [x9]\nadd w10, w10, {}\nstrb w10, [x9]\n",
+                        load("x10", *offset as i64),
+                        n
+                    ),
+                ),
+                Op::Set(0, n) => write(result, format_args!("mov w0, {}\n", n)),
+                Op::Set(offset, n) => write(
+                    result,
+                    format_args!(
+                        "{}add x9, x1, x10\nmov w10, {}\nstrb w10, [x9]\n",
+                        load("x10", *offset as i64),
+                        n
+                    ),
+                ),
+                Op::MulAdd(offset, n) => write(
+                    result,
+                    format_args!(
+                        "and w11, w0, 0xff\nmov w12, {}\nmul w11, w11, w12\n{}add x9, x1, x10\nldrb w10, [x9]\nadd w10, w10, w11\nstrb w10, [x9]\n",
+                        n,
+                        load("x10", *offset as i64)
+                    ),
+                ),
+                // Only moves of less than 4096 fit in an 'add' or 'sub'.
+                Op::Move(n) if n.unsigned_abs() >= 4096 => write(
+                    result,
+                    format_args!(
+                        "strb w0, [x1]\n{}add x1, x1, x10\nldrb w0, [x1]\n",
+                        load("x10", *n as i64)
+                    ),
+                ),
+                Op::Move(n) if *n < 0 => write(
+                    result,
+                    format_args!("strb w0, [x1]\nsub x1, x1, {}\nldrb w0, [x1]\n", -n),
+                ),
+                Op::Move(n) => write(
+                    result,
+                    format_args!("strb w0, [x1]\nadd x1, x1, {}\nldrb w0, [x1]\n", n),
+                ),
+                Op::Scan(stride) => {
+                    self.scan = true;
+
+                    write(result, format_args!("{}", scan(*stride, self.memory_size)))
+                }
+                Op::If(body) => {
+                    let id = self.loops;
+                    self.loops += 1;
+
+                    write(result, format_args!("cbz w0, _{}_end\n", id))?;
+                    self.generate_ops(body, result)?;
+                    write(result, format_args!("_{}_end:\n", id))
+                }
+                Op::Loop(body) => {
+                    let id = self.loops;
+                    self.loops += 1;
+
+                    write(result, format_args!("cbz w0, _{}_end\n_{}:\n", id, id))?;
+                    self.generate_ops(body, result)?;
+                    write(result, format_args!("cbnz w0, _{}\n_{}_end:\n", id, id))
+                }
+                // Procedures are routines that share x1 and w0 with their caller,
+                // with a frame big enough for the scratch slots that reading and writing use.
+                // The table of procedures holds the address of a routine for each cell value.
+                Op::Procedure(body) => {
+                    let id = self.procs;
+                    self.procs += 1;
+
+                    let mut routine = String::new();
+
+                    write(
+                        &mut routine,
+                        format_args!("_proc_{}:\nsub sp, sp, 32\nstr x30, [sp, 24]\n", id),
+                    )?;
+                    self.generate_ops(body, &mut routine)?;
+                    write(
+                        &mut routine,
+                        format_args!("ldr x30, [sp, 24]\nadd sp, sp, 32\nret\n"),
+                    )?;
+
+                    self.procedures.push_str(&routine);
+
+                    write(
+                        result,
+                        format_args!(
+                            "adrp x9, procs@PAGE\nadd x9, x9, procs@PAGEOFF\nadr x10, _proc_{}\nand w11, w0, 0xff\nstr x10, [x9, x11, lsl 3]\n",
+                            id
+                        ),
+                    )
+                }
+                // Calling a procedure that was never defined does nothing.
+                Op::Call => write(
+                    result,
+                    format_args!(
+                        "adrp x9, procs@PAGE\nadd x9, x9, procs@PAGEOFF\nand w11, w0, 0xff\nldr x10, [x9, x11, lsl 3]\ncbz x10, 1f\nblr x10\n1:\n"
+                    ),
+                ),
+                Op::Fork(_) => unreachable!("forks are rejected before generating"),
+                Op::Debug => {
+                    self.debug = true;
+
+                    write(result, format_args!("strb w0, [x1]\nbl _debug\n"))
+                }
+                Op::Output => write(
+                    result,
+                    format_args!(
+                        "strb w0, [x1]\nstr x1, [sp, 8]\nstr x16, [sp, 16]\nmov w16, 4\nmov w0, 1\nmov w2, 1\nsvc 0x80\nldr x1, [sp, 8]\nldr x16, [sp, 16]\nldrb w0, [x1]\n"
+                    ),
+                ),
+                Op::Input => {
+                    // Embedded input is read first, then stdin once it runs out.
+                    if self.input_len > 0 {
+                        write(
+                            result,
+                            format_args!(
+                                "adrp x9, input_pos@PAGE\nadd x9, x9, input_pos@PAGEOFF\nldr x10, [x9]\ncmp x10, {}\nb.hs 1f\nadrp x11, input@PAGE\nadd x11, x11, input@PAGEOFF\nldrb w0, [x11, x10]\nadd x10, x10, 1\nstr x10, [x9]\nb 2f\n1:\n",
+                                self.input_len
+                            ),
+                        )?;
+                    }
+
+                    write(
+                        result,
+                        format_args!(
+                            "strb w0, [x1]\nstr x1, [sp, 8]\nstr x16, [sp, 16]\nmov w16, 3\nmov w0, 0\nmov w2, 1\nsvc 0x80\nldr x1, [sp, 8]\nldr x16, [sp, 16]\nldrb w0, [x1]\n"
+                        ),
+                    )?;
+
+                    if self.input_len > 0 {
+                        write(result, format_args!("2:\n"))?;
+                    }
+
+                    Ok(())
+                }
+                // The storage cell of Extended Brainfuck lives in w19.
+                // Shifting left and inverting can leave a byte, so their results are masked.
+                Op::End => write(result, format_args!("b _end\n")),
+                Op::Store => write(result, format_args!("and w19, w0, 0xff\n")),
+                Op::Fetch => write(result, format_args!("mov w0, w19\n")),
+                Op::ShiftRight => {
+                    write(result, format_args!("and w0, w0, 0xff\nlsr w0, w0, 1\n"))
+                }
+                Op::ShiftLeft => {
+                    write(result, format_args!("lsl w0, w0, 1\nand w0, w0, 0xff\n"))
+                }
+                Op::Not => write(result, format_args!("mvn w0, w0\nand w0, w0, 0xff\n")),
+                Op::Xor => write(result, format_args!("eor w0, w0, w19\nand w0, w0, 0xff\n")),
+                Op::And => write(result, format_args!("and w0, w0, w19\n")),
+                Op::Or => write(result, format_args!("orr w0, w0, w19\nand w0, w0, 0xff\n")),
+            }?;
+        }
+
+        Ok(())
+    }
+}
+
 pub fn generator_linux_arm64(
-    tokens: Vec<Token>,
+    program: Vec<Op>,
     memory_size: usize,
+    input: &[u8],
 ) -> Result<String, Box<dyn Error>> {
+    if let Some(span) = find_fork(&program) {
+        return Err(Box::new(XBFError::from(
+            Diagnostic::error("Brainfork is only supported by the 'llvm' target")
+                .with_span(span, "this forks the program"),
+        )));
+    }
+
     let mut result = format!(
         ".global _main
 
 .bss
 .comm mem, {}
+.comm procs, 2048
+.comm input_pos, 8
+",
+        memory_size
+    );
+
+    if !input.is_empty() {
+        let bytes: Vec<String> = input.iter().map(|b| b.to_string()).collect();
+
+        write(
+            &mut result,
+            format_args!("\n.data\ninput:\n.byte {}\n", bytes.join(", ")),
+        )?;
+    }
 
+    write(
+        &mut result,
+        format_args!(
+            "
 .text
 _main:
 sub sp, sp, 16
 adrp x1, mem@PAGE
 add x1, x1, mem@PAGEOFF
-mov w0, 0\n",
-        memory_size
-    );
+mov w0, 0
+mov w19, 0\n"
+        ),
+    )?;
 
-    let brackets = match_brackets(&tokens)?;
+    let mut generator = Generator {
+        procedures: String::new(),
+        loops: 0,
+        procs: 0,
+        input_len: input.len(),
+        debug: false,
+        scan: false,
+        memory_size,
+    };
 
-    for (i, t) in tokens.iter().enumerate() {
-        match t.get_typ() {
-            TokenType::Inc(size) => write(&mut result, format_args!("add w0, w0, {}\n", size)),
-            TokenType::Dec(size) => write(&mut result, format_args!("sub w0, w0, {}\n", size)),
-            TokenType::Left(size) => write(
-                &mut result,
-                format_args!("strb w0, [x1]\nsub x1, x1, {}\nldrb w0, [x1]\n", size),
-            ),
-            TokenType::Right(size) => write(
-                &mut result,
-                format_args!("strb w0, [x1]\nadd x1, x1, {}\nldrb w0, [x1]\n", size),
-            ),
-            TokenType::BracketOpen => write(
-                &mut result,
-                format_args!("cbz w0, _{}\n_{}:\n", brackets.get(&i).unwrap(), i),
-            ),
-            TokenType::BracketClose => write(
-                &mut result,
-                format_args!("cbnz w0, _{}\n_{}:\n", brackets.get(&i).unwrap(), i),
-            ),
-            TokenType::PutChar => write(
-                &mut result,
-                format_args!(
-                    "strb w0, [x1]\nstr x1, [sp, 8]\nstr x16, [sp, 16]\nmov w16, 4\nmov w0, 1\nmov w2, 1\nsvc 0x80\nldr x1, [sp, 8]\nldr x16, [sp, 16]\nldrb w0, [x1]\n"
-                ),
-            ),
-            TokenType::GetChar => write(
-                &mut result,
-                format_args!(
-                    "strb w0, [x1]\nstr x1, [sp, 8]\nstr x16, [sp, 16]\nmov w16, 3\nmov w0, 0\nmov w2, 1\nsvc 0x80\nldr x1, [sp, 8]\nldr x16, [sp, 16]\nldrb w0, [x1]\n"
-                ),
-            ),
-        }?;
-    }
+    generator.generate_ops(&program, &mut result)?;
 
     write(
         &mut result,
-        format_args!("add sp, sp, 16\nmov w16, 1\nmov w0, 0\nsvc 0x80"),
+        format_args!("_end:\nadd sp, sp, 16\nmov w16, 1\nmov w0, 0\nsvc 0x80\n"),
     )?;
 
+    result.push_str(&generator.procedures);
+
+    if generator.scan {
+        write(
+            &mut result,
+            format_args!(
+                "_off_tape:
+adrp x1, off_tape_msg@PAGE
+add x1, x1, off_tape_msg@PAGEOFF
+mov x2, {}
+mov x0, 2
+mov x16, 4
+svc 0x80
+mov x0, 1
+mov x16, 1
+svc 0x80
+
+.data
+off_tape_msg:
+.ascii \"{}\\n\"
+
+.text
+",
+                OFF_TAPE.len() + 1,
+                OFF_TAPE
+            ),
+        )?;
+    }
+
+    if generator.debug {
+        result.push_str(&debug_routines(memory_size));
+
+        write(
+            &mut result,
+            format_args!(
+                "
+.data
+debug_pointer:
+.asciz \"pointer \"
+debug_cells:
+.asciz \", cells from \"
+
+.bss
+.comm debug_buf, 256
+"
+            ),
+        )?;
+    }
+
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that every decimal immediate fits its instruction:
+    /// 16 bits for moves, and 12 bits for additions and subtractions.
+    fn assert_encodable(asm: &str) {
+        for line in asm.lines() {
+            let Some((inst, operands)) = line.split_once(' ') else {
+                continue;
+            };
+
+            let limit = match inst {
+                "mov" | "movz" | "movk" => 0xffff,
+                "add" | "sub" => 0xfff,
+                _ => continue,
+            };
+
+            let imm = operands.split(", ").nth(match inst {
+                "add" | "sub" => 2,
+                _ => 1,
+            });
+
+            if let Some(Ok(imm)) = imm.map(str::parse::<u64>) {
+                assert!(imm <= limit, "{} doesn't fit its immediate", line);
+            }
+        }
+    }
+
+    #[test]
+    fn large_offsets_fit() {
+        let program = vec![
+            Op::Debug,
+            Op::Add(70000, 1),
+            Op::Set(-70000, 2),
+            Op::MulAdd(5000, 3),
+            Op::Move(5000),
+            Op::Move(-5000),
+            Op::Scan(5000),
+            Op::Scan(-70000),
+        ];
+
+        let asm = generator_linux_arm64(program, 100000, &[]).unwrap();
+
+        assert_encodable(&asm);
+        assert!(asm.contains("movz x12, 34448\nmovk x12, 1, lsl 16\ncmp x11, x12\n"));
+    }
+
+    #[test]
+    fn small_offsets_stay_short() {
+        let asm = generator_linux_arm64(vec![Op::Add(2, 1), Op::Move(-3)], 30000, &[]).unwrap();
+
+        assert_encodable(&asm);
+        assert!(asm.contains("movz x10, 2\nadd x9, x1, x10\n"));
+        assert!(asm.contains("sub x1, x1, 3\n"));
+    }
+}
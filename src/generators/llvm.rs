@@ -1,16 +1,16 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
-use crate::{
-    generators::common::match_brackets,
-    lexer::{Token, TokenType},
-};
+use crate::{common::XBFError, diagnostic::Diagnostic, ir::Op};
 
 use inkwell::{
+    basic_block::BasicBlock,
     builder::{Builder, BuilderError},
     context::Context,
-    types::ArrayType,
-    values::{BasicValueEnum, PointerValue},
-    AddressSpace,
+    module::{Linkage, Module},
+    targets::TargetMachine,
+    types::{ArrayType, FunctionType, StructType},
+    values::{BasicMetadataValueEnum, BasicValueEnum, FunctionValue, GlobalValue, PointerValue},
+    AddressSpace, AtomicOrdering, AtomicRMWBinOp,
 };
 
 /*
@@ -38,227 +38,1126 @@ fn emit(&self) {
 }
 */
 
+/// How many cells the debug command prints.
+const DEBUG_CELLS: u64 = 16;
+
+/// Whether the program uses Brainfork anywhere.
+fn forks(ops: &[Op]) -> bool {
+    ops.iter().any(|op| match op {
+        Op::Fork(_) => true,
+        Op::Loop(body) | Op::Procedure(body) => forks(body),
+        _ => false,
+    })
+}
+
 fn format_vars(vars: &mut usize) -> String {
     let str = format!("_var_{}", vars);
     *vars += 1;
     str
 }
 
-fn access_cell<'ctx>(
+/// What Brainfork needs while lowering the program into the function every thread runs.
+struct Threads<'ctx> {
+    /// The state of the running thread: its tape, index, storage cell,
+    /// the fork it resumes from, and the list of threads it started.
+    state: PointerValue<'ctx>,
+    state_type: StructType<'ctx>,
+    /// An entry in the list of started threads: the thread, its state, and the next entry.
+    child_type: StructType<'ctx>,
+    run: FunctionValue<'ctx>,
+    pthread_create: FunctionValue<'ctx>,
+    /// Runs each child to the end before the parent continues, instead of starting a thread.
+    deterministic: bool,
+    /// Where each thread starts: the start of the program, then after each fork.
+    resumes: Vec<BasicBlock<'ctx>>,
+}
+
+/// State shared while lowering the program into the body of `main`,
+/// or of a pbrain procedure.
+struct Emitter<'a, 'ctx> {
     context: &'ctx Context,
-    builder: &'ctx Builder<'ctx>,
-    mem: &PointerValue<'ctx>,
-    mem_array_type: &ArrayType<'ctx>,
-    idxp: &PointerValue<'ctx>,
-    vars: &mut usize,
-) -> Result<(PointerValue<'ctx>, BasicValueEnum<'ctx>), BuilderError> {
-    let mut subvars = vars.clone();
-
-    let idx = builder.build_load(context.i64_type(), *idxp, &format_vars(&mut subvars))?;
-
-    let elem_ptr = unsafe {
-        builder.build_in_bounds_gep(
-            *mem_array_type,
-            *mem,
-            &[context.i32_type().const_zero(), idx.into_int_value()],
-            &format_vars(&mut subvars),
-        )
-    }?;
-
-    let cell = builder.build_load(context.i8_type(), elem_ptr, &format_vars(&mut subvars))?;
-
-    *vars = subvars;
-
-    Ok((elem_ptr, cell))
+    module: &'a Module<'ctx>,
+    builder: &'a Builder<'ctx>,
+    function: FunctionValue<'ctx>,
+    mem: PointerValue<'ctx>,
+    mem_array_type: ArrayType<'ctx>,
+    idxp: PointerValue<'ctx>,
+    storage: PointerValue<'ctx>,
+    putc_fmt: GlobalValue<'ctx>,
+    putc: FunctionValue<'ctx>,
+    getc: FunctionValue<'ctx>,
+    exit: FunctionValue<'ctx>,
+    /// The input embedded in the program and how much of it has been read,
+    /// if there is any.
+    input: Option<(GlobalValue<'ctx>, ArrayType<'ctx>, GlobalValue<'ctx>)>,
+    /// The pbrain procedure table, holding a function pointer for each cell value.
+    procs: GlobalValue<'ctx>,
+    proc_type: FunctionType<'ctx>,
+    /// The function behind the debug command, once it is needed.
+    debug: Option<FunctionValue<'ctx>>,
+    /// Only set outside procedures, in programs that fork.
+    threads: Option<Threads<'ctx>>,
+    vars: usize,
+    loops: usize,
+    procedures: usize,
 }
 
-pub fn generator_llvm(
-    tokens: Vec<Token>,
-    memory_size: usize,
-    file: &String,
-) -> Result<String, Box<dyn Error>> {
-    let context = Context::create();
-    let module = context.create_module(file);
-    let builder = context.create_builder();
+impl<'ctx> Emitter<'_, 'ctx> {
+    fn var(&mut self) -> String {
+        format_vars(&mut self.vars)
+    }
 
-    let putc_fmt = module.add_global(context.i8_type().array_type(2), None, "putc_fmt");
+    fn access_cell(&mut self) -> Result<(PointerValue<'ctx>, BasicValueEnum<'ctx>), BuilderError> {
+        self.access_cell_at(0)
+    }
 
-    {
-        let i8_type = context.i8_type();
-        let putc_fmt_a = i8_type.const_int(b'%' as u64, false);
-        let putc_fmt_b = i8_type.const_int(b'c' as u64, false);
+    /// Returns a pointer to the cell at an offset from the current one, and its value.
+    fn access_cell_at(
+        &mut self,
+        offset: isize,
+    ) -> Result<(PointerValue<'ctx>, BasicValueEnum<'ctx>), BuilderError> {
+        let mut idx = self
+            .builder
+            .build_load(self.context.i64_type(), self.idxp, &self.var())?
+            .into_int_value();
 
-        putc_fmt.set_initializer(&i8_type.const_array(&[putc_fmt_a, putc_fmt_b]));
+        if offset != 0 {
+            idx = self.builder.build_int_add(
+                idx,
+                self.context.i64_type().const_int(offset as u64, true),
+                &self.var(),
+            )?;
+        }
+
+        let elem_ptr = unsafe {
+            self.builder.build_in_bounds_gep(
+                self.mem_array_type,
+                self.mem,
+                &[self.context.i32_type().const_zero(), idx],
+                &self.var(),
+            )
+        }?;
+
+        let cell = self
+            .builder
+            .build_load(self.context.i8_type(), elem_ptr, &self.var())?;
+
+        Ok((elem_ptr, cell))
     }
 
-    let putc_fn_type = context
-        .i32_type()
-        .fn_type(&[context.ptr_type(AddressSpace::default()).into()], true);
-    let putc = module.add_function("printf", putc_fn_type, None);
+    /// Returns a pointer to the entry of the procedure table for the current cell.
+    fn access_proc(&mut self) -> Result<PointerValue<'ctx>, BuilderError> {
+        let (_, cell) = self.access_cell()?;
 
-    let getc_fn_type = context.i32_type().fn_type(&[], false);
-    let getc = module.add_function("getchar", getc_fn_type, None);
+        let idx = self.builder.build_int_z_extend(
+            cell.into_int_value(),
+            self.context.i64_type(),
+            &self.var(),
+        )?;
 
-    let main_fn_type = context.i32_type().fn_type(&[], false);
-    let function_main = module.add_function("main", main_fn_type, None);
-    let basic_block = context.append_basic_block(function_main, "start");
+        let table_type = self
+            .context
+            .ptr_type(AddressSpace::default())
+            .array_type(256);
 
-    builder.position_at_end(basic_block);
+        unsafe {
+            self.builder.build_in_bounds_gep(
+                table_type,
+                self.procs.as_pointer_value(),
+                &[self.context.i32_type().const_zero(), idx],
+                &self.var(),
+            )
+        }
+    }
 
-    let idxp = builder.build_alloca(context.i64_type(), "idx")?;
-    builder.build_store(idxp, context.i64_type().const_zero())?;
+    /// Emits the body of a procedure as a function of its own,
+    /// which takes the tape, the index and the storage cell of its caller.
+    fn emit_procedure(&mut self, body: &[Op]) -> Result<FunctionValue<'ctx>, Box<dyn Error>> {
+        let id = self.procedures;
+        self.procedures += 1;
 
-    let mem_array_type = context.i8_type().array_type(memory_size.try_into()?);
+        let function =
+            self.module
+                .add_function(format!("_proc_{}", id).as_str(), self.proc_type, None);
 
-    let mem = builder.build_alloca(mem_array_type, "mem")?;
+        let caller_block = self.builder.get_insert_block().unwrap();
 
-    let brackets = match_brackets(&tokens)?;
+        self.builder
+            .position_at_end(self.context.append_basic_block(function, "start"));
 
-    let mut blocks: HashMap<usize, inkwell::basic_block::BasicBlock> = HashMap::new();
+        let params = function.get_params();
 
-    for (i, t) in tokens.iter().enumerate() {
-        match t.get_typ() {
-            TokenType::BracketOpen => {
-                let block =
-                    context.append_basic_block(function_main, format!("_br_{}", i).as_str());
-                blocks.insert(i, block);
-            }
-            TokenType::BracketClose => {
-                let block =
-                    context.append_basic_block(function_main, format!("_br_{}", i).as_str());
-                blocks.insert(i, block);
-            }
-            _ => (),
+        let mut emitter = Emitter {
+            function,
+            mem: params[0].into_pointer_value(),
+            idxp: params[1].into_pointer_value(),
+            storage: params[2].into_pointer_value(),
+            threads: None,
+            vars: 0,
+            loops: 0,
+            ..*self
+        };
+
+        emitter.emit_ops(body)?;
+
+        self.builder.build_return(None)?;
+
+        self.procedures = emitter.procedures;
+        self.debug = emitter.debug;
+
+        self.builder.position_at_end(caller_block);
+
+        Ok(function)
+    }
+
+    /// Returns the function behind the debug command, defining it the first time.
+    /// It writes the pointer and a row of cells holding it to stderr, like
+    /// `pointer 18, cells from 16: 0 0 72 101 ...`.
+    fn debug_function(&mut self) -> Result<FunctionValue<'ctx>, Box<dyn Error>> {
+        if let Some(function) = self.debug {
+            return Ok(function);
+        }
+
+        let context = self.context;
+        let builder = self.builder;
+
+        let i64_type = context.i64_type();
+        let ptr_type = context.ptr_type(AddressSpace::default());
+
+        let dprintf_type = context
+            .i32_type()
+            .fn_type(&[context.i32_type().into(), ptr_type.into()], true);
+        let dprintf = self.libc_function("dprintf", dprintf_type);
+
+        let function = self.module.add_function(
+            "xbf_debug",
+            context
+                .void_type()
+                .fn_type(&[ptr_type.into(), i64_type.into()], false),
+            Some(Linkage::Internal),
+        );
+
+        let caller_block = builder.get_insert_block().unwrap();
+
+        builder.position_at_end(context.append_basic_block(function, "start"));
+
+        let mem = function.get_nth_param(0).unwrap().into_pointer_value();
+        let idx = function.get_nth_param(1).unwrap().into_int_value();
+
+        let memory_size = self.mem_array_type.len() as u64;
+        let count = DEBUG_CELLS.min(memory_size);
+
+        // The row of cells holding the pointer, moved back if it would run past the tape.
+        let row = builder.build_and(idx, i64_type.const_int(!15, false), "row")?;
+        let last = i64_type.const_int(memory_size - count, false);
+        let fits = builder.build_int_compare(inkwell::IntPredicate::ULT, row, last, "fits")?;
+        let start = builder
+            .build_select(fits, row, last, "start")?
+            .into_int_value();
+
+        let fmt = format!(
+            "pointer %lld, cells from %lld:{}\n",
+            " %d".repeat(count as usize)
+        );
+        let fmt = builder.build_global_string_ptr(&fmt, "debug_fmt")?;
+
+        let mut args: Vec<BasicMetadataValueEnum> = vec![
+            context.i32_type().const_int(2, false).into(),
+            fmt.as_pointer_value().into(),
+            idx.into(),
+            start.into(),
+        ];
+
+        for i in 0..count {
+            let cell_idx = builder.build_int_add(start, i64_type.const_int(i, false), "")?;
+
+            let cell_ptr =
+                unsafe { builder.build_in_bounds_gep(context.i8_type(), mem, &[cell_idx], "") }?;
+
+            let cell = builder.build_load(context.i8_type(), cell_ptr, "")?;
+
+            args.push(
+                builder
+                    .build_int_z_extend(cell.into_int_value(), context.i32_type(), "")?
+                    .into(),
+            );
+        }
+
+        builder.build_call(dprintf, &args, "")?;
+        builder.build_return(None)?;
+
+        builder.position_at_end(caller_block);
+
+        self.debug = Some(function);
+
+        Ok(function)
+    }
+
+    /// Returns a function from libc, declaring it the first time.
+    fn libc_function(&self, name: &str, fn_type: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        match self.module.get_function(name) {
+            Some(function) => function,
+            None => self.module.add_function(name, fn_type, None),
+        }
+    }
+
+    /// Branches to a new block that ends the program with an error,
+    /// for a scan that found no zero cell before the edge of the tape.
+    fn emit_off_tape(&mut self) -> Result<BasicBlock<'ctx>, Box<dyn Error>> {
+        let context = self.context;
+        let builder = self.builder;
+
+        let block = builder.get_insert_block().unwrap();
+        let block_off =
+            context.append_basic_block(self.function, &format!("_off_tape_{}", self.vars));
+
+        builder.position_at_end(block_off);
+
+        let msg = match self.module.get_global("off_tape_msg") {
+            Some(msg) => msg,
+            None => builder.build_global_string_ptr(
+                "error: a scan for a zero cell ran off the tape\n",
+                "off_tape_msg",
+            )?,
+        };
+
+        let dprintf = self.libc_function(
+            "dprintf",
+            context.i32_type().fn_type(
+                &[
+                    context.i32_type().into(),
+                    context.ptr_type(AddressSpace::default()).into(),
+                ],
+                true,
+            ),
+        );
+
+        builder.build_call(
+            dprintf,
+            &[
+                context.i32_type().const_int(2, false).into(),
+                msg.as_pointer_value().into(),
+            ],
+            &self.var(),
+        )?;
+        builder.build_call(
+            self.exit,
+            &[context.i32_type().const_int(1, false).into()],
+            &self.var(),
+        )?;
+        builder.build_unreachable()?;
+
+        builder.position_at_end(block);
+
+        Ok(block_off)
+    }
+
+    /// Emits a scan for a zero cell, which ends the program with an error
+    /// if it would leave the tape.
+    /// Scans by one cell call `memchr`, or `memrchr` to the left where libc has it.
+    fn emit_scan(&mut self, stride: isize) -> Result<(), Box<dyn Error>> {
+        let context = self.context;
+        let builder = self.builder;
+
+        let i64_type = context.i64_type();
+        let ptr_type = context.ptr_type(AddressSpace::default());
+        let size = i64_type.const_int(self.mem_array_type.len() as u64, false);
+
+        // memrchr is a GNU extension, which macOS doesn't have.
+        let gnu = self
+            .module
+            .get_triple()
+            .as_str()
+            .to_string_lossy()
+            .contains("linux");
+
+        let name = match stride {
+            1 => Some("memchr"),
+            -1 if gnu => Some("memrchr"),
+            _ => None,
+        };
+
+        let block_off = self.emit_off_tape()?;
+
+        let Some(name) = name else {
+            // Other strides step through the tape, checking its edges on the way.
+            let id = self.loops;
+            self.loops += 1;
+
+            let block_check =
+                context.append_basic_block(self.function, format!("_scan_{}", id).as_str());
+            let block_step =
+                context.append_basic_block(self.function, format!("_scan_step_{}", id).as_str());
+            let block_end =
+                context.append_basic_block(self.function, format!("_scan_end_{}", id).as_str());
+
+            builder.build_unconditional_branch(block_check)?;
+
+            builder.position_at_end(block_check);
+
+            let (_, cell) = self.access_cell()?;
+
+            let cmp = builder.build_int_compare(
+                inkwell::IntPredicate::EQ,
+                cell.into_int_value(),
+                context.i8_type().const_zero(),
+                &self.var(),
+            )?;
+
+            builder.build_conditional_branch(cmp, block_end, block_step)?;
+
+            builder.position_at_end(block_step);
+
+            let idx = builder
+                .build_load(i64_type, self.idxp, &self.var())?
+                .into_int_value();
+
+            let next =
+                builder.build_int_add(idx, i64_type.const_int(stride as u64, true), &self.var())?;
+
+            builder.build_store(self.idxp, next)?;
+
+            // Past the start wraps around, so one unsigned comparison checks both edges.
+            let off =
+                builder.build_int_compare(inkwell::IntPredicate::UGE, next, size, &self.var())?;
+
+            builder.build_conditional_branch(off, block_off, block_check)?;
+
+            builder.position_at_end(block_end);
+
+            return Ok(());
+        };
+
+        let search = self.libc_function(
+            name,
+            ptr_type.fn_type(
+                &[ptr_type.into(), context.i32_type().into(), i64_type.into()],
+                false,
+            ),
+        );
+
+        let idx = builder
+            .build_load(i64_type, self.idxp, &self.var())?
+            .into_int_value();
+
+        let (start, len) = if stride == 1 {
+            let (cell_ptr, _) = self.access_cell()?;
+            let len = builder.build_int_sub(size, idx, &self.var())?;
+
+            (cell_ptr, len)
+        } else {
+            let len = builder.build_int_add(idx, i64_type.const_int(1, false), &self.var())?;
+
+            (self.mem, len)
+        };
+
+        let found = builder
+            .build_call(
+                search,
+                &[
+                    start.into(),
+                    context.i32_type().const_zero().into(),
+                    len.into(),
+                ],
+                &self.var(),
+            )?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value();
+
+        let block_found =
+            context.append_basic_block(self.function, &format!("_found_{}", self.vars));
+
+        let none = builder.build_is_null(found, &self.var())?;
+
+        builder.build_conditional_branch(none, block_off, block_found)?;
+
+        builder.position_at_end(block_found);
+
+        let found_idx = builder.build_ptr_diff(context.i8_type(), found, self.mem, &self.var())?;
+
+        builder.build_store(self.idxp, found_idx)?;
+
+        Ok(())
+    }
+
+    fn emit_ops(&mut self, ops: &[Op]) -> Result<(), Box<dyn Error>> {
+        for op in ops {
+            self.emit_op(op)?;
         }
+
+        Ok(())
     }
 
-    let mut vars: usize = 0;
+    fn emit_op(&mut self, op: &Op) -> Result<(), Box<dyn Error>> {
+        let context = self.context;
+        let builder = self.builder;
 
-    for (i, t) in tokens.iter().enumerate() {
-        match t.get_typ() {
-            TokenType::Inc(n) => {
-                let (elem_ptr, cell) =
-                    access_cell(&context, &builder, &mem, &mem_array_type, &idxp, &mut vars)?;
+        match op {
+            Op::Add(offset, n) => {
+                let (elem_ptr, cell) = self.access_cell_at(*offset)?;
 
                 let result = builder.build_int_add(
                     cell.into_int_value(),
                     context.i8_type().const_int(*n as u64, false),
-                    &format_vars(&mut vars),
+                    &self.var(),
                 )?;
 
                 builder.build_store(elem_ptr, result)?;
             }
-            TokenType::Dec(n) => {
-                let (elem_ptr, cell) =
-                    access_cell(&context, &builder, &mem, &mem_array_type, &idxp, &mut vars)?;
+            Op::Set(offset, n) => {
+                let (elem_ptr, _) = self.access_cell_at(*offset)?;
 
-                let result = builder.build_int_sub(
+                builder.build_store(elem_ptr, context.i8_type().const_int(*n as u64, false))?;
+            }
+            Op::MulAdd(offset, n) => {
+                let (_, cell) = self.access_cell()?;
+                let (elem_ptr, target) = self.access_cell_at(*offset)?;
+
+                let product = builder.build_int_mul(
                     cell.into_int_value(),
                     context.i8_type().const_int(*n as u64, false),
-                    &format_vars(&mut vars),
+                    &self.var(),
                 )?;
 
+                let result =
+                    builder.build_int_add(target.into_int_value(), product, &self.var())?;
+
                 builder.build_store(elem_ptr, result)?;
             }
-            TokenType::Left(n) => {
-                let idx = builder.build_load(context.i64_type(), idxp, &format_vars(&mut vars))?;
+            Op::Move(n) => {
+                let idx = builder.build_load(context.i64_type(), self.idxp, &self.var())?;
 
-                let result = builder.build_int_sub(
+                let result = builder.build_int_add(
                     idx.into_int_value(),
-                    context.i64_type().const_int(*n as u64, false),
-                    &format_vars(&mut vars),
+                    context.i64_type().const_int(*n as u64, true),
+                    &self.var(),
                 )?;
 
-                builder.build_store(idxp, result)?;
+                builder.build_store(self.idxp, result)?;
             }
-            TokenType::Right(n) => {
-                let idx = builder.build_load(context.i64_type(), idxp, &format_vars(&mut vars))?;
+            Op::Scan(stride) => self.emit_scan(*stride)?,
+            Op::Loop(body) => {
+                let id = self.loops;
+                self.loops += 1;
 
-                let result = builder.build_int_add(
-                    idx.into_int_value(),
-                    context.i64_type().const_int(*n as u64, false),
-                    &format_vars(&mut vars),
+                let block_body =
+                    context.append_basic_block(self.function, format!("_loop_{}", id).as_str());
+                let block_end =
+                    context.append_basic_block(self.function, format!("_loop_end_{}", id).as_str());
+
+                let zero = context.i8_type().const_zero();
+
+                // '[': skip the body when the cell is zero.
+                let (_, cell) = self.access_cell()?;
+
+                let cmp = builder.build_int_compare(
+                    inkwell::IntPredicate::EQ,
+                    cell.into_int_value(),
+                    zero,
+                    &self.var(),
                 )?;
 
-                builder.build_store(idxp, result)?;
-            }
-            TokenType::BracketOpen | TokenType::BracketClose => {
-                let (_, cell) =
-                    access_cell(&context, &builder, &mem, &mem_array_type, &idxp, &mut vars)?;
+                builder.build_conditional_branch(cmp, block_end, block_body)?;
 
-                let zero = context.i8_type().const_zero();
+                builder.position_at_end(block_body);
+
+                self.emit_ops(body)?;
+
+                // ']': jump back to the start of the body while the cell is nonzero.
+                let (_, cell) = self.access_cell()?;
 
                 let cmp = builder.build_int_compare(
-                    if *t.get_typ() == TokenType::BracketClose {
-                        inkwell::IntPredicate::NE
-                    } else {
-                        inkwell::IntPredicate::EQ
-                    },
+                    inkwell::IntPredicate::NE,
                     cell.into_int_value(),
                     zero,
-                    &format_vars(&mut vars),
+                    &self.var(),
                 )?;
 
-                let alt = brackets.get(&i).unwrap();
+                builder.build_conditional_branch(cmp, block_body, block_end)?;
 
-                let block_then = blocks.get(alt).unwrap();
-                let block_else = blocks.get(&i).unwrap();
+                builder.position_at_end(block_end);
+            }
+            Op::If(body) => {
+                let id = self.loops;
+                self.loops += 1;
+
+                let block_body =
+                    context.append_basic_block(self.function, format!("_if_{}", id).as_str());
+                let block_end =
+                    context.append_basic_block(self.function, format!("_if_end_{}", id).as_str());
+
+                let (_, cell) = self.access_cell()?;
+
+                let cmp = builder.build_int_compare(
+                    inkwell::IntPredicate::EQ,
+                    cell.into_int_value(),
+                    context.i8_type().const_zero(),
+                    &self.var(),
+                )?;
+
+                builder.build_conditional_branch(cmp, block_end, block_body)?;
 
-                builder.build_conditional_branch(cmp, *block_then, *block_else)?;
+                builder.position_at_end(block_body);
 
-                builder.build_unconditional_branch(*block_else)?;
+                self.emit_ops(body)?;
 
-                builder.position_at_end(*block_else);
+                builder.build_unconditional_branch(block_end)?;
+
+                builder.position_at_end(block_end);
             }
-            TokenType::PutChar => {
-                let (_, cell) =
-                    access_cell(&context, &builder, &mem, &mem_array_type, &idxp, &mut vars)?;
+            Op::Fork(span) => {
+                let (state, state_type, child_type, run, pthread_create, deterministic) =
+                    match &self.threads {
+                        Some(t) => (
+                            t.state,
+                            t.state_type,
+                            t.child_type,
+                            t.run,
+                            t.pthread_create,
+                            t.deterministic,
+                        ),
+                        None => {
+                            return Err(Box::new(XBFError::from(
+                                Diagnostic::error("can't fork inside a procedure")
+                                    .with_span(*span, "this forks the program")
+                                    .with_note("a forked thread can't return from a procedure"),
+                            )))
+                        }
+                    };
+
+                let ptr_type = context.ptr_type(AddressSpace::default());
+
+                // The child starts as a copy of the parent, one cell to the right,
+                // and resumes after this fork.
+                let child = builder.build_malloc(state_type, &self.var())?;
+
+                builder.build_memcpy(child, 8, state, 8, state_type.size_of().unwrap())?;
+
+                let idx = builder
+                    .build_load(context.i64_type(), self.idxp, &self.var())?
+                    .into_int_value();
+
+                let next = builder.build_int_add(
+                    idx,
+                    context.i64_type().const_int(1, false),
+                    &self.var(),
+                )?;
+
+                let child_mem = builder.build_struct_gep(state_type, child, 0, &self.var())?;
+                let child_idxp = builder.build_struct_gep(state_type, child, 1, &self.var())?;
+                let child_resume = builder.build_struct_gep(state_type, child, 3, &self.var())?;
+                let child_children = builder.build_struct_gep(state_type, child, 4, &self.var())?;
+
+                let child_cell = unsafe {
+                    builder.build_in_bounds_gep(
+                        self.mem_array_type,
+                        child_mem,
+                        &[context.i32_type().const_zero(), next],
+                        &self.var(),
+                    )
+                }?;
+
+                let resume = self.threads.as_ref().unwrap().resumes.len();
+
+                builder.build_store(child_idxp, next)?;
+                builder.build_store(child_cell, context.i8_type().const_int(1, false))?;
+                builder.build_store(
+                    child_resume,
+                    context.i32_type().const_int(resume as u64, false),
+                )?;
+                builder.build_store(child_children, ptr_type.const_null())?;
+
+                if deterministic {
+                    builder.build_call(run, &[child.into()], &self.var())?;
+                    builder.build_free(child)?;
+                } else {
+                    // Remember the thread, so it can be joined when this one ends.
+                    let entry = builder.build_malloc(child_type, &self.var())?;
+
+                    let children = builder.build_struct_gep(state_type, state, 4, &self.var())?;
+                    let first = builder.build_load(ptr_type, children, &self.var())?;
+
+                    let entry_thread =
+                        builder.build_struct_gep(child_type, entry, 0, &self.var())?;
+                    let entry_state =
+                        builder.build_struct_gep(child_type, entry, 1, &self.var())?;
+                    let entry_next = builder.build_struct_gep(child_type, entry, 2, &self.var())?;
+
+                    builder.build_store(entry_state, child)?;
+                    builder.build_store(entry_next, first)?;
+                    builder.build_store(children, entry)?;
+
+                    builder.build_call(
+                        pthread_create,
+                        &[
+                            entry_thread.into(),
+                            ptr_type.const_null().into(),
+                            run.as_global_value().as_pointer_value().into(),
+                            child.into(),
+                        ],
+                        &self.var(),
+                    )?;
+                }
+
+                let (elem_ptr, _) = self.access_cell()?;
+
+                builder.build_store(elem_ptr, context.i8_type().const_zero())?;
+
+                let block_resume = context
+                    .append_basic_block(self.function, format!("_resume_{}", resume).as_str());
+
+                builder.build_unconditional_branch(block_resume)?;
+
+                builder.position_at_end(block_resume);
+
+                self.threads.as_mut().unwrap().resumes.push(block_resume);
+            }
+            Op::Debug => {
+                let function = self.debug_function()?;
+
+                let idx = builder.build_load(context.i64_type(), self.idxp, &self.var())?;
+
+                builder.build_call(function, &[self.mem.into(), idx.into()], "")?;
+            }
+            Op::Output => {
+                let (_, cell) = self.access_cell()?;
 
                 let zero = context.i32_type().const_zero();
 
                 let elem_ptr = unsafe {
                     builder.build_gep(
                         context.ptr_type(AddressSpace::default()),
-                        putc_fmt.as_pointer_value(),
+                        self.putc_fmt.as_pointer_value(),
                         &[zero, zero],
-                        &format_vars(&mut vars),
+                        &self.var(),
                     )
                 }?;
 
-                builder.build_call(
-                    putc,
-                    &[elem_ptr.into(), cell.into()],
-                    &format_vars(&mut vars),
-                )?;
+                builder.build_call(self.putc, &[elem_ptr.into(), cell.into()], &self.var())?;
             }
-            TokenType::GetChar => {
-                let ch = builder.build_call(getc, &[], &format_vars(&mut vars))?;
-                builder.build_call(getc, &[], &format_vars(&mut vars))?; // Remove newline
+            Op::Input => {
+                // Embedded input is read first, then stdin once it runs out.
+                let block_end = match self.input {
+                    Some((input, input_type, input_pos)) => {
+                        let id = self.vars;
+
+                        let block_input = context
+                            .append_basic_block(self.function, format!("_input_{}", id).as_str());
+                        let block_stdin = context
+                            .append_basic_block(self.function, format!("_stdin_{}", id).as_str());
+                        let block_end = context.append_basic_block(
+                            self.function,
+                            format!("_getc_end_{}", id).as_str(),
+                        );
+
+                        // Forked threads share the input, so each read takes its byte
+                        // atomically. Reads past the end keep counting, which is harmless.
+                        let pos = builder.build_atomicrmw(
+                            AtomicRMWBinOp::Add,
+                            input_pos.as_pointer_value(),
+                            context.i64_type().const_int(1, false),
+                            AtomicOrdering::SequentiallyConsistent,
+                        )?;
+
+                        let cmp = builder.build_int_compare(
+                            inkwell::IntPredicate::ULT,
+                            pos,
+                            context.i64_type().const_int(input_type.len() as u64, false),
+                            &self.var(),
+                        )?;
+
+                        builder.build_conditional_branch(cmp, block_input, block_stdin)?;
+
+                        builder.position_at_end(block_input);
+
+                        let ch_ptr = unsafe {
+                            builder.build_in_bounds_gep(
+                                input_type,
+                                input.as_pointer_value(),
+                                &[context.i32_type().const_zero(), pos],
+                                &self.var(),
+                            )
+                        }?;
+
+                        let ch = builder.build_load(context.i8_type(), ch_ptr, &self.var())?;
+
+                        let (elem_ptr, _) = self.access_cell()?;
+
+                        builder.build_store(elem_ptr, ch)?;
+
+                        builder.build_unconditional_branch(block_end)?;
+
+                        builder.position_at_end(block_stdin);
+
+                        Some(block_end)
+                    }
+                    None => None,
+                };
+
+                let ch = builder.build_call(self.getc, &[], &self.var())?;
+                builder.build_call(self.getc, &[], &self.var())?; // Remove newline
 
                 let trunc = builder.build_int_truncate(
                     ch.try_as_basic_value().left().unwrap().into_int_value(),
                     context.i8_type(),
-                    &format_vars(&mut vars),
+                    &self.var(),
                 )?;
 
-                let idx = builder.build_load(context.i64_type(), idxp, &format_vars(&mut vars))?;
+                let (elem_ptr, _) = self.access_cell()?;
 
-                let elem_ptr = unsafe {
-                    builder.build_in_bounds_gep(
-                        mem_array_type,
-                        mem,
-                        &[context.i32_type().const_zero(), idx.into_int_value()],
-                        &format_vars(&mut vars),
-                    )
+                builder.build_store(elem_ptr, trunc)?;
+
+                if let Some(block_end) = block_end {
+                    builder.build_unconditional_branch(block_end)?;
+
+                    builder.position_at_end(block_end);
+                }
+            }
+            Op::End => {
+                // '@' may be inside a procedure, so exit instead of returning from main.
+                builder.build_call(
+                    self.exit,
+                    &[context.i32_type().const_zero().into()],
+                    &self.var(),
+                )?;
+                builder.build_unreachable()?;
+
+                // Anything after '@' is unreachable, but still needs a block to go in.
+                let block = context.append_basic_block(self.function, "_after_end");
+                builder.position_at_end(block);
+            }
+            Op::Procedure(body) => {
+                let function = self.emit_procedure(body)?;
+
+                let entry = self.access_proc()?;
+
+                builder.build_store(entry, function.as_global_value().as_pointer_value())?;
+            }
+            Op::Call => {
+                let id = self.vars;
+
+                let block_call =
+                    context.append_basic_block(self.function, format!("_call_{}", id).as_str());
+                let block_end =
+                    context.append_basic_block(self.function, format!("_call_end_{}", id).as_str());
+
+                let entry = self.access_proc()?;
+
+                let function = builder
+                    .build_load(
+                        context.ptr_type(AddressSpace::default()),
+                        entry,
+                        &self.var(),
+                    )?
+                    .into_pointer_value();
+
+                // Calling a procedure that was never defined does nothing.
+                let cmp = builder.build_is_null(function, &self.var())?;
+
+                builder.build_conditional_branch(cmp, block_end, block_call)?;
+
+                builder.position_at_end(block_call);
+
+                builder.build_indirect_call(
+                    self.proc_type,
+                    function,
+                    &[self.mem.into(), self.idxp.into(), self.storage.into()],
+                    &self.var(),
+                )?;
+
+                builder.build_unconditional_branch(block_end)?;
+
+                builder.position_at_end(block_end);
+            }
+            Op::Store => {
+                let (_, cell) = self.access_cell()?;
+
+                builder.build_store(self.storage, cell)?;
+            }
+            Op::Fetch => {
+                let (elem_ptr, _) = self.access_cell()?;
+
+                let stored = builder.build_load(context.i8_type(), self.storage, &self.var())?;
+
+                builder.build_store(elem_ptr, stored)?;
+            }
+            Op::ShiftRight | Op::ShiftLeft | Op::Not | Op::Xor | Op::And | Op::Or => {
+                let (elem_ptr, cell) = self.access_cell()?;
+                let cell = cell.into_int_value();
+
+                let one = context.i8_type().const_int(1, false);
+
+                let stored = builder
+                    .build_load(context.i8_type(), self.storage, &self.var())?
+                    .into_int_value();
+
+                let result = match op {
+                    Op::ShiftRight => builder.build_right_shift(cell, one, false, &self.var()),
+                    Op::ShiftLeft => builder.build_left_shift(cell, one, &self.var()),
+                    Op::Not => builder.build_not(cell, &self.var()),
+                    Op::Xor => builder.build_xor(cell, stored, &self.var()),
+                    Op::And => builder.build_and(cell, stored, &self.var()),
+                    _ => builder.build_or(cell, stored, &self.var()),
                 }?;
 
-                builder.build_store(elem_ptr, trunc)?;
+                builder.build_store(elem_ptr, result)?;
             }
         }
+
+        Ok(())
     }
+}
 
-    builder.position_at_end(function_main.get_last_basic_block().unwrap());
+pub fn generator_llvm(
+    program: Vec<Op>,
+    memory_size: usize,
+    input: &[u8],
+    deterministic: bool,
+    file: &str,
+) -> Result<String, Box<dyn Error>> {
+    let context = Context::create();
+    let module = context.create_module(file);
+    module.set_triple(&TargetMachine::get_default_triple());
+    let builder = context.create_builder();
+
+    let putc_fmt = module.add_global(context.i8_type().array_type(2), None, "putc_fmt");
+
+    {
+        let i8_type = context.i8_type();
+        let putc_fmt_a = i8_type.const_int(b'%' as u64, false);
+        let putc_fmt_b = i8_type.const_int(b'c' as u64, false);
+
+        putc_fmt.set_initializer(&i8_type.const_array(&[putc_fmt_a, putc_fmt_b]));
+    }
+
+    let putc_fn_type = context
+        .i32_type()
+        .fn_type(&[context.ptr_type(AddressSpace::default()).into()], true);
+    let putc = module.add_function("printf", putc_fn_type, None);
+
+    let getc_fn_type = context.i32_type().fn_type(&[], false);
+    let getc = module.add_function("getchar", getc_fn_type, None);
+
+    let exit_fn_type = context
+        .void_type()
+        .fn_type(&[context.i32_type().into()], false);
+    let exit = module.add_function("exit", exit_fn_type, None);
+
+    let input = if input.is_empty() {
+        None
+    } else {
+        let input_type = context.i8_type().array_type(input.len().try_into()?);
+
+        let input_global = module.add_global(input_type, None, "input");
+        input_global.set_constant(true);
+        input_global.set_initializer(&context.const_string(input, false));
+
+        let input_pos = module.add_global(context.i64_type(), None, "input_pos");
+        input_pos.set_initializer(&context.i64_type().const_zero());
+
+        Some((input_global, input_type, input_pos))
+    };
+
+    let ptr_type = context.ptr_type(AddressSpace::default());
+
+    let procs = module.add_global(ptr_type.array_type(256), None, "procs");
+    procs.set_initializer(&ptr_type.array_type(256).const_zero());
+
+    let proc_type = context
+        .void_type()
+        .fn_type(&[ptr_type.into(), ptr_type.into(), ptr_type.into()], false);
+
+    let main_fn_type = context.i32_type().fn_type(&[], false);
+    let function_main = module.add_function("main", main_fn_type, None);
+    let basic_block = context.append_basic_block(function_main, "start");
+
+    builder.position_at_end(basic_block);
+
+    let mem_array_type = context.i8_type().array_type(memory_size.try_into()?);
+
+    let mut emitter = Emitter {
+        context: &context,
+        module: &module,
+        builder: &builder,
+        function: function_main,
+        mem: ptr_type.const_null(),
+        mem_array_type,
+        idxp: ptr_type.const_null(),
+        storage: ptr_type.const_null(),
+        putc_fmt,
+        putc,
+        getc,
+        exit,
+        input,
+        procs,
+        proc_type,
+        debug: None,
+        threads: None,
+        vars: 0,
+        loops: 0,
+        procedures: 0,
+    };
+
+    if !forks(&program) {
+        emitter.idxp = builder.build_alloca(context.i64_type(), "idx")?;
+        builder.build_store(emitter.idxp, context.i64_type().const_zero())?;
+
+        emitter.mem = builder.build_alloca(mem_array_type, "mem")?;
+        // The tape starts zeroed, which the optimizations also count on.
+        builder.build_memset(
+            emitter.mem,
+            8,
+            context.i8_type().const_zero(),
+            mem_array_type.size_of().unwrap(),
+        )?;
+
+        emitter.storage = builder.build_alloca(context.i8_type(), "storage")?;
+        builder.build_store(emitter.storage, context.i8_type().const_zero())?;
+
+        emitter.emit_ops(&program)?;
+
+        builder.build_return(Some(&context.i32_type().const_zero()))?;
+
+        return Ok(module.to_string());
+    }
+
+    // With Brainfork, the program runs in a function that every thread starts in,
+    // taking a state that holds everything a thread has of its own.
+    let state_type = context.struct_type(
+        &[
+            mem_array_type.into(),
+            context.i64_type().into(),
+            context.i8_type().into(),
+            context.i32_type().into(),
+            ptr_type.into(),
+        ],
+        false,
+    );
+    let child_type = context.struct_type(
+        &[context.i64_type().into(), ptr_type.into(), ptr_type.into()],
+        false,
+    );
+
+    let pthread_create_type = context.i32_type().fn_type(
+        &[
+            ptr_type.into(),
+            ptr_type.into(),
+            ptr_type.into(),
+            ptr_type.into(),
+        ],
+        false,
+    );
+    let pthread_create = module.add_function("pthread_create", pthread_create_type, None);
+
+    let pthread_join_type = context
+        .i32_type()
+        .fn_type(&[context.i64_type().into(), ptr_type.into()], false);
+    let pthread_join = module.add_function("pthread_join", pthread_join_type, None);
+
+    let run = module.add_function("xbf_run", ptr_type.fn_type(&[ptr_type.into()], false), None);
+
+    // main runs the program from the start in a zeroed state.
+    let state = builder.build_alloca(state_type, "state")?;
+    builder.build_memset(
+        state,
+        8,
+        context.i8_type().const_zero(),
+        state_type.size_of().unwrap(),
+    )?;
+    builder.build_call(run, &[state.into()], "")?;
     builder.build_return(Some(&context.i32_type().const_zero()))?;
 
+    let block_entry = context.append_basic_block(run, "start");
+
+    builder.position_at_end(block_entry);
+
+    let state = run.get_nth_param(0).unwrap().into_pointer_value();
+
+    emitter.function = run;
+    emitter.mem = builder.build_struct_gep(state_type, state, 0, "mem")?;
+    emitter.idxp = builder.build_struct_gep(state_type, state, 1, "idx")?;
+    emitter.storage = builder.build_struct_gep(state_type, state, 2, "storage")?;
+
+    let resume_ptr = builder.build_struct_gep(state_type, state, 3, "resume_ptr")?;
+    let resume = builder
+        .build_load(context.i32_type(), resume_ptr, "resume")?
+        .into_int_value();
+
+    let block_start = context.append_basic_block(run, "_resume_0");
+
+    emitter.threads = Some(Threads {
+        state,
+        state_type,
+        child_type,
+        run,
+        pthread_create,
+        deterministic,
+        resumes: vec![block_start],
+    });
+
+    builder.position_at_end(block_start);
+
+    emitter.emit_ops(&program)?;
+
+    // Wait for every thread this one started before ending.
+    let block_join = context.append_basic_block(run, "_join");
+    let block_join_next = context.append_basic_block(run, "_join_next");
+    let block_done = context.append_basic_block(run, "_done");
+
+    builder.build_unconditional_branch(block_join)?;
+
+    builder.position_at_end(block_join);
+
+    let children = builder.build_struct_gep(state_type, state, 4, "children")?;
+    let entry = builder
+        .build_load(ptr_type, children, "entry")?
+        .into_pointer_value();
+    let none_left = builder.build_is_null(entry, "none_left")?;
+
+    builder.build_conditional_branch(none_left, block_done, block_join_next)?;
+
+    builder.position_at_end(block_join_next);
+
+    let entry_thread = builder.build_struct_gep(child_type, entry, 0, "entry_thread")?;
+    let entry_state = builder.build_struct_gep(child_type, entry, 1, "entry_state")?;
+    let entry_next = builder.build_struct_gep(child_type, entry, 2, "entry_next")?;
+
+    let thread = builder.build_load(context.i64_type(), entry_thread, "thread")?;
+
+    builder.build_call(
+        pthread_join,
+        &[thread.into(), ptr_type.const_null().into()],
+        "",
+    )?;
+
+    let child = builder
+        .build_load(ptr_type, entry_state, "child")?
+        .into_pointer_value();
+    let next = builder.build_load(ptr_type, entry_next, "next")?;
+
+    builder.build_free(child)?;
+    builder.build_free(entry)?;
+    builder.build_store(children, next)?;
+    builder.build_unconditional_branch(block_join)?;
+
+    builder.position_at_end(block_done);
+    builder.build_return(Some(&ptr_type.const_null()))?;
+
+    // Each thread jumps to where it was forked.
+    let resumes = emitter.threads.unwrap().resumes;
+
+    builder.position_at_end(block_entry);
+
+    let cases: Vec<_> = resumes
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, block)| (context.i32_type().const_int(i as u64, false), *block))
+        .collect();
+
+    builder.build_switch(resume, resumes[0], &cases)?;
+
     Ok(module.to_string())
 }
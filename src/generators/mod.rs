@@ -1,3 +1,3 @@
-mod common;
+pub mod bf;
 pub mod linux_arm64;
 pub mod llvm;
@@ -0,0 +1,71 @@
+use crate::{
+    common::Span,
+    parser::{Node, NodeType},
+};
+
+pub mod passes;
+
+/// An operation of the mid-level representation that every generator lowers,
+/// so optimizations only have to be written once.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Adds to the cell at an offset from the pointer, wrapping around at 256.
+    Add(isize, u8),
+    /// Sets the cell at an offset from the pointer.
+    Set(isize, u8),
+    /// Adds the current cell times a factor to the cell at an offset from the pointer.
+    MulAdd(isize, u8),
+    /// Moves the pointer.
+    Move(isize),
+    /// Moves the pointer by a stride until it reaches a zero cell.
+    Scan(isize),
+    Loop(Vec<Op>),
+    /// Runs its body once if the current cell isn't zero.
+    If(Vec<Op>),
+    Output,
+    Input,
+    /// A pbrain procedure, defined under the number in the current cell when it is reached.
+    Procedure(Vec<Op>),
+    Call,
+    /// A Brainfork fork, with where it was written for errors about it.
+    Fork(Span),
+    Debug,
+    End,
+    Store,
+    Fetch,
+    ShiftRight,
+    ShiftLeft,
+    Not,
+    Xor,
+    And,
+    Or,
+}
+
+/// Lowers the program tree one node to one operation, without optimizing anything.
+pub fn lower(nodes: &[Node]) -> Vec<Op> {
+    nodes
+        .iter()
+        .map(|n| match n.get_typ() {
+            NodeType::Inc(n) => Op::Add(0, (*n % 256) as u8),
+            NodeType::Dec(n) => Op::Add(0, (256 - *n % 256) as u8),
+            NodeType::Left(n) => Op::Move(-(*n as isize)),
+            NodeType::Right(n) => Op::Move(*n as isize),
+            NodeType::Loop(body) => Op::Loop(lower(body)),
+            NodeType::Procedure(body) => Op::Procedure(lower(body)),
+            NodeType::Call => Op::Call,
+            NodeType::Fork => Op::Fork(n.get_span()),
+            NodeType::Debug => Op::Debug,
+            NodeType::PutChar => Op::Output,
+            NodeType::GetChar => Op::Input,
+            NodeType::End => Op::End,
+            NodeType::Store => Op::Store,
+            NodeType::Fetch => Op::Fetch,
+            NodeType::ShiftRight => Op::ShiftRight,
+            NodeType::ShiftLeft => Op::ShiftLeft,
+            NodeType::Not => Op::Not,
+            NodeType::Xor => Op::Xor,
+            NodeType::And => Op::And,
+            NodeType::Or => Op::Or,
+        })
+        .collect()
+}
@@ -0,0 +1,412 @@
+use super::Op;
+
+/// Runs the passes whose results can still be written as source.
+pub fn simplify(ops: Vec<Op>) -> Vec<Op> {
+    dead_loops(fold(ops), true)
+}
+
+/// Runs every optimization over the program, for the generators of machine code.
+pub fn optimize(ops: Vec<Op>) -> Vec<Op> {
+    let ops = mul_loops(clear_loops(simplify(ops)));
+    let ops = scan_loops(ops);
+
+    // Loops become stores, so there is more to fold and more loops known to be dead.
+    dead_loops(fold(ops), true)
+}
+
+/// Turns loops that only move the pointer, like `[>]` and `[<<]`, into a search for a zero cell.
+fn scan_loops(ops: Vec<Op>) -> Vec<Op> {
+    ops.into_iter()
+        .map(|op| match op {
+            Op::Loop(body) => match body.as_slice() {
+                [Op::Move(n)] => Op::Scan(*n),
+                _ => Op::Loop(scan_loops(body)),
+            },
+            Op::Procedure(body) => Op::Procedure(scan_loops(body)),
+            op => op,
+        })
+        .collect()
+}
+
+/// Turns loops that only count the cell down or up to zero, like `[-]` and `[+]`, into a store.
+/// Counting by an even amount would never reach zero from an odd value, so those stay loops.
+fn clear_loops(ops: Vec<Op>) -> Vec<Op> {
+    ops.into_iter()
+        .map(|op| match op {
+            Op::Loop(body) => match body.as_slice() {
+                [Op::Add(0, n)] if n % 2 == 1 => Op::Set(0, 0),
+                _ => Op::Loop(clear_loops(body)),
+            },
+            Op::Procedure(body) => Op::Procedure(clear_loops(body)),
+            op => op,
+        })
+        .collect()
+}
+
+/// Returns what each iteration of a loop adds to the cells around the pointer, in the order
+/// they are first changed, if the body only adds and moves, and ends where it started.
+fn loop_effect(body: &[Op]) -> Option<Vec<(isize, u8)>> {
+    let mut adds: Vec<(isize, u8)> = Vec::new();
+    let mut pos = 0;
+
+    for op in body {
+        match op {
+            Op::Add(offset, n) => match adds.iter_mut().find(|(o, _)| *o == pos + offset) {
+                Some((_, total)) => *total = total.wrapping_add(*n),
+                None => adds.push((pos + offset, *n)),
+            },
+            Op::Move(n) => pos += n,
+            _ => return None,
+        }
+    }
+
+    (pos == 0).then_some(adds)
+}
+
+/// Turns loops that add multiples of the current cell to other cells, like `[->+>+++<<]`,
+/// into a multiply-add for each of them followed by clearing the cell.
+/// The loop cell has to go down or up by one each time, so it runs as many times as its value,
+/// or 256 minus it. Like the loop, the multiply-adds only run if the cell isn't zero,
+/// since the cells they reach may be off the tape otherwise.
+fn mul_loops(ops: Vec<Op>) -> Vec<Op> {
+    let mut out: Vec<Op> = Vec::new();
+
+    for op in ops {
+        match op {
+            Op::Loop(body) => {
+                let effect = loop_effect(&body);
+                let step = effect
+                    .as_ref()
+                    .and_then(|adds| adds.iter().find(|(o, _)| *o == 0))
+                    .map(|(_, n)| *n);
+
+                match (effect, step) {
+                    (Some(adds), Some(step @ (1 | 255))) => {
+                        let mut body: Vec<Op> = Vec::new();
+
+                        for (offset, n) in adds {
+                            if offset != 0 && n != 0 {
+                                // Counting up runs 256 minus the cell times, which is minus it.
+                                let factor = if step == 255 { n } else { n.wrapping_neg() };
+                                body.push(Op::MulAdd(offset, factor));
+                            }
+                        }
+
+                        if body.is_empty() {
+                            out.push(Op::Set(0, 0));
+                        } else {
+                            body.push(Op::Set(0, 0));
+                            out.push(Op::If(body));
+                        }
+                    }
+                    _ => out.push(Op::Loop(mul_loops(body))),
+                }
+            }
+            Op::Procedure(body) => out.push(Op::Procedure(mul_loops(body))),
+            op => out.push(op),
+        }
+    }
+
+    out
+}
+
+/// Merges neighbouring additions to the same cell, and neighbouring moves,
+/// into one net operation, which is dropped if it does nothing.
+/// Additions right after a store to the same cell are merged into it,
+/// and a store makes the additions right before it to the same cell useless.
+/// The lexer only merges runs of the same command, so `++-+` would otherwise be three additions.
+fn fold(ops: Vec<Op>) -> Vec<Op> {
+    let mut out: Vec<Op> = Vec::new();
+
+    for op in ops {
+        let op = match op {
+            Op::Loop(body) => Op::Loop(fold(body)),
+            Op::Procedure(body) => Op::Procedure(fold(body)),
+            op => op,
+        };
+
+        // Dropping an operation that does nothing can leave two more to merge, as in `+<>-`.
+        match (out.last(), op) {
+            (Some(Op::Add(prev_offset, prev)), Op::Add(offset, n)) if *prev_offset == offset => {
+                let net = prev.wrapping_add(n);
+                out.pop();
+
+                if net != 0 {
+                    out.push(Op::Add(offset, net));
+                }
+            }
+            (Some(Op::Set(prev_offset, v)), Op::Add(offset, n)) if *prev_offset == offset => {
+                let v = v.wrapping_add(n);
+                out.pop();
+                out.push(Op::Set(offset, v));
+            }
+            (Some(Op::Add(prev_offset, _) | Op::Set(prev_offset, _)), Op::Set(offset, v))
+                if *prev_offset == offset =>
+            {
+                out.pop();
+                out.push(Op::Set(offset, v));
+            }
+            (Some(Op::Move(prev)), Op::Move(n)) => {
+                let net = prev + n;
+                out.pop();
+
+                if net != 0 {
+                    out.push(Op::Move(net));
+                }
+            }
+            (_, Op::Add(_, 0) | Op::Move(0)) => (),
+            (_, op) => out.push(op),
+        }
+    }
+
+    out
+}
+
+/// Removes loops that can never run, because the cell is known to be zero where they start.
+/// `start` is whether `ops` run at the start of the program, where every cell is zero.
+fn dead_loops(ops: Vec<Op>, start: bool) -> Vec<Op> {
+    let mut out: Vec<Op> = Vec::new();
+
+    for op in ops {
+        match op {
+            Op::Loop(body) => {
+                // The cell is also zero right after a loop ends, after it is cleared,
+                // after a multiply loop, and where a scan stops.
+                let dead = match out.last() {
+                    None => start,
+                    Some(prev) => {
+                        matches!(prev, Op::Loop(_) | Op::If(_) | Op::Set(0, 0) | Op::Scan(_))
+                    }
+                };
+
+                if !dead {
+                    out.push(Op::Loop(dead_loops(body, false)));
+                }
+            }
+            Op::Procedure(body) => out.push(Op::Procedure(dead_loops(body, false))),
+            op => out.push(op),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ir::lower, lexer::Lexer, parser::Parser, source::SourceMap};
+
+    /// Lexes, parses and lowers `code` without optimizing it.
+    fn ops(code: &str) -> Vec<Op> {
+        let mut sources = SourceMap::new();
+        let id = sources.add("<test>", code.as_bytes().to_vec()).unwrap();
+        let tokens = Lexer::new(sources.get(id).get_text(), id).lex();
+
+        match Parser::new(tokens, &sources).parse() {
+            Ok(nodes) => lower(&nodes),
+            Err(_) => panic!("'{}' doesn't parse", code),
+        }
+    }
+
+    #[test]
+    fn fold_merges_mixed_runs() {
+        assert_eq!(fold(ops("++-+")), vec![Op::Add(0, 2)]);
+        assert_eq!(fold(ops("--+")), vec![Op::Add(0, 255)]);
+        assert_eq!(fold(ops(">><>")), vec![Op::Move(2)]);
+        assert_eq!(fold(ops("<<>")), vec![Op::Move(-1)]);
+    }
+
+    #[test]
+    fn fold_drops_what_does_nothing() {
+        assert_eq!(fold(ops("><><")), vec![]);
+        assert_eq!(fold(ops("+<>-")), vec![]);
+        assert_eq!(fold(ops(&"+".repeat(256))), vec![]);
+    }
+
+    #[test]
+    fn fold_keeps_other_ops_between() {
+        assert_eq!(
+            fold(ops("+.+>,>")),
+            vec![
+                Op::Add(0, 1),
+                Op::Output,
+                Op::Add(0, 1),
+                Op::Move(1),
+                Op::Input,
+                Op::Move(1),
+            ]
+        );
+        assert_eq!(
+            fold(ops(",[+--><>]")),
+            vec![Op::Input, Op::Loop(vec![Op::Add(0, 255), Op::Move(1)])]
+        );
+    }
+
+    #[test]
+    fn dead_loops_at_start_and_after_loops() {
+        assert_eq!(
+            simplify(ops("[.]+[.][,]")),
+            vec![Op::Add(0, 1), Op::Loop(vec![Op::Output])]
+        );
+        assert_eq!(simplify(ops("[.]+-[,]")), vec![]);
+    }
+
+    #[test]
+    fn dead_loops_keeps_the_first_loop_in_a_body() {
+        assert_eq!(
+            simplify(ops("+[[.]]")),
+            vec![Op::Add(0, 1), Op::Loop(vec![Op::Loop(vec![Op::Output])])]
+        );
+    }
+
+    #[test]
+    fn clear_loops_with_odd_steps() {
+        assert_eq!(
+            clear_loops(fold(ops(",[-]"))),
+            vec![Op::Input, Op::Set(0, 0)]
+        );
+        assert_eq!(
+            clear_loops(fold(ops(",[+]"))),
+            vec![Op::Input, Op::Set(0, 0)]
+        );
+        assert_eq!(
+            clear_loops(fold(ops(",[---]"))),
+            vec![Op::Input, Op::Set(0, 0)]
+        );
+    }
+
+    #[test]
+    fn clear_loops_leaves_even_steps_and_moves() {
+        assert_eq!(
+            clear_loops(fold(ops(",[--]"))),
+            vec![Op::Input, Op::Loop(vec![Op::Add(0, 254)])]
+        );
+        assert_eq!(
+            clear_loops(fold(ops(",[->]"))),
+            vec![Op::Input, Op::Loop(vec![Op::Add(0, 255), Op::Move(1)])]
+        );
+    }
+
+    #[test]
+    fn fold_merges_into_stores() {
+        assert_eq!(optimize(ops(",[-]+++")), vec![Op::Input, Op::Set(0, 3)]);
+        assert_eq!(optimize(ops(",+[-]")), vec![Op::Input, Op::Set(0, 0)]);
+    }
+
+    #[test]
+    fn dead_loops_after_stores() {
+        assert_eq!(
+            dead_loops(vec![Op::Set(0, 0), Op::Loop(vec![Op::Output])], false),
+            vec![Op::Set(0, 0)]
+        );
+        assert_eq!(
+            dead_loops(vec![Op::Set(0, 1), Op::Loop(vec![Op::Output])], false),
+            vec![Op::Set(0, 1), Op::Loop(vec![Op::Output])]
+        );
+    }
+
+    #[test]
+    fn mul_loops_counting_down() {
+        assert_eq!(
+            mul_loops(fold(ops(",[->+>+++<<]"))),
+            vec![
+                Op::Input,
+                Op::If(vec![Op::MulAdd(1, 1), Op::MulAdd(2, 3), Op::Set(0, 0)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn mul_loops_counting_up() {
+        // Counting up runs 256 minus the cell times, so taking one each time adds the cell.
+        assert_eq!(
+            mul_loops(fold(ops(",[+<->]"))),
+            vec![Op::Input, Op::If(vec![Op::MulAdd(-1, 1), Op::Set(0, 0)])]
+        );
+    }
+
+    #[test]
+    fn mul_loops_leaves_unbalanced_and_uneven_loops() {
+        assert_eq!(
+            mul_loops(fold(ops(",[->+<<]"))),
+            vec![
+                Op::Input,
+                Op::Loop(vec![
+                    Op::Add(0, 255),
+                    Op::Move(1),
+                    Op::Add(0, 1),
+                    Op::Move(-2),
+                ]),
+            ]
+        );
+        assert_eq!(
+            mul_loops(fold(ops(",[-->+<]"))),
+            vec![
+                Op::Input,
+                Op::Loop(vec![
+                    Op::Add(0, 254),
+                    Op::Move(1),
+                    Op::Add(0, 1),
+                    Op::Move(-1),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn mul_loops_inside_other_loops() {
+        assert_eq!(
+            mul_loops(fold(ops(",[->[-<+>]<]"))),
+            vec![
+                Op::Input,
+                Op::Loop(vec![
+                    Op::Add(0, 255),
+                    Op::Move(1),
+                    Op::If(vec![Op::MulAdd(-1, 1), Op::Set(0, 0)]),
+                    Op::Move(-1),
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dead_loops_after_mul_loops() {
+        let mul = Op::If(vec![Op::MulAdd(1, 1), Op::Set(0, 0)]);
+
+        assert_eq!(
+            dead_loops(vec![mul.clone(), Op::Loop(vec![Op::Output])], false),
+            vec![mul]
+        );
+    }
+
+    #[test]
+    fn scan_loops_with_any_stride() {
+        assert_eq!(scan_loops(fold(ops(",[>]"))), vec![Op::Input, Op::Scan(1)]);
+        assert_eq!(scan_loops(fold(ops(",[<]"))), vec![Op::Input, Op::Scan(-1)]);
+        assert_eq!(scan_loops(fold(ops(",[>>]"))), vec![Op::Input, Op::Scan(2)]);
+        assert_eq!(
+            scan_loops(fold(ops(",[<><<]"))),
+            vec![Op::Input, Op::Scan(-2)]
+        );
+    }
+
+    #[test]
+    fn scan_loops_leaves_loops_that_do_more() {
+        assert_eq!(
+            scan_loops(fold(ops(",[>+]"))),
+            vec![Op::Input, Op::Loop(vec![Op::Move(1), Op::Add(0, 1)])]
+        );
+        assert_eq!(
+            scan_loops(fold(ops(",[>.]"))),
+            vec![Op::Input, Op::Loop(vec![Op::Move(1), Op::Output])]
+        );
+    }
+
+    #[test]
+    fn dead_loops_after_scans() {
+        assert_eq!(
+            dead_loops(vec![Op::Scan(1), Op::Loop(vec![Op::Output])], false),
+            vec![Op::Scan(1)]
+        );
+    }
+}
@@ -1,46 +1,104 @@
 use std::fmt::{Debug, Display};
 
-use crate::common::try_index;
+use crate::{
+    common::{FileId, Span},
+    diagnostic::Diagnostic,
+};
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// The kind of a token, with the length of the run for commands that repeat.
+/// Runs are counted in 32 bits to keep tokens small,
+/// and longer runs are split into several tokens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TokenType {
-    Inc(usize),
-    Dec(usize),
-    Left(usize),
-    Right(usize),
+    Inc(u32),
+    Dec(u32),
+    Left(u32),
+    Right(u32),
     BracketOpen,
     BracketClose,
     PutChar,
     GetChar,
+    /// `@` from Extended Brainfuck Type I.
+    End,
+    /// `$` from Extended Brainfuck Type I.
+    Store,
+    /// `!` from Extended Brainfuck Type I.
+    Fetch,
+    /// `}` from Extended Brainfuck Type I.
+    ShiftRight,
+    /// `{` from Extended Brainfuck Type I.
+    ShiftLeft,
+    /// `~` from Extended Brainfuck Type I.
+    Not,
+    /// `^` from Extended Brainfuck Type I.
+    Xor,
+    /// `&` from Extended Brainfuck Type I.
+    And,
+    /// `|` from Extended Brainfuck Type I.
+    Or,
+    /// `(` from pbrain.
+    ProcOpen,
+    /// `)` from pbrain.
+    ProcClose,
+    /// `:` from pbrain.
+    Call,
+    /// `Y` from Brainfork.
+    Fork,
+    /// `#`, which prints the pointer and some of the tape when debugging is enabled.
+    Debug,
 }
 
+/// Extensions to the core language that add commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    /// Extended Brainfuck Type I: `@`, `$`, `!`, `}`, `{`, `~`, `^`, `&` and `|`,
+    /// which work with the current cell and a single storage cell.
+    Ebf1,
+    /// pbrain: `(` and `)` define the procedure numbered by the current cell,
+    /// and `:` calls the procedure numbered by the current cell.
+    Pbrain,
+    /// Brainfork: `Y` forks the program into a thread that gets a copy of the tape.
+    Brainfork,
+}
+
+impl Dialect {
+    pub fn from_name(name: &str) -> Option<Dialect> {
+        match name {
+            "ebf1" => Some(Dialect::Ebf1),
+            "pbrain" => Some(Dialect::Pbrain),
+            "brainfork" => Some(Dialect::Brainfork),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 pub struct Token {
     typ: TokenType,
-    col: usize,
-    ln: usize,
+    span: Span,
 }
 
 impl Token {
-    pub fn new(typ: TokenType, col: usize, ln: usize) -> Token {
-        Token {
-            typ: typ,
-            col: col,
-            ln: ln,
-        }
+    pub fn new(typ: TokenType, span: Span) -> Token {
+        Token { typ, span }
     }
 
     pub fn get_typ(&self) -> &TokenType {
         &self.typ
     }
 
-    pub fn err(&self) -> String {
-        format!("Error on line {}, col {}:", self.ln, self.col)
+    pub fn get_span(&self) -> Span {
+        self.span
     }
 }
 
 impl Debug for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "<{:?}, {}, {}>", self.typ, self.col, self.ln)
+        write!(
+            f,
+            "<{:?}, {}, {}, {}..{}>",
+            self.typ, self.span.col, self.span.ln, self.span.start, self.span.end
+        )
     }
 }
 
@@ -54,30 +112,132 @@ impl PartialEq<TokenType> for Token {
     fn eq(&self, other: &TokenType) -> bool {
         self.typ == *other
     }
+}
 
-    fn ne(&self, other: &TokenType) -> bool {
-        !(self == other)
+/// Conventions for comments that may contain command characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommentStyle {
+    /// `#` up to the end of the line.
+    Hash,
+    /// `//` up to the end of the line.
+    Slash,
+    /// A loop at the very start of the program, which can never run.
+    Loop,
+}
+
+impl CommentStyle {
+    pub fn from_name(name: &str) -> Option<CommentStyle> {
+        match name {
+            "hash" => Some(CommentStyle::Hash),
+            "slash" => Some(CommentStyle::Slash),
+            "loop" => Some(CommentStyle::Loop),
+            _ => None,
+        }
     }
 }
 
-pub struct Lexer {
-    text: Vec<u8>,
+/// Splits source bytes into tokens in a single pass, without copying the source.
+pub struct Lexer<'a> {
+    text: &'a [u8],
+    file: FileId,
+    utf8: bool,
     idx: usize,
     col: usize,
     ln: usize,
+    comments: Vec<CommentStyle>,
+    dialects: Vec<Dialect>,
+    strict: bool,
+    debug: bool,
+    input_separator: bool,
+    input: &'a [u8],
+    /// How much of the text comes before the input separator.
+    code_len: usize,
+    /// Everything before this index has already been checked for prose in strict mode.
+    checked: usize,
+    warnings: Vec<Diagnostic>,
+    origins: Option<&'a [Span]>,
 }
 
-impl Lexer {
-    pub fn new(text: &str) -> Lexer {
+impl<'a> Lexer<'a> {
+    /// Creates a lexer over raw source bytes.
+    /// Columns count characters if the source is valid UTF-8, and bytes otherwise.
+    pub fn new(text: &'a [u8], file: FileId) -> Lexer<'a> {
         Lexer {
-            text: text.into(),
+            text,
+            file,
+            utf8: std::str::from_utf8(text).is_ok(),
             idx: 0,
             col: 1,
             ln: 1,
+            comments: Vec::new(),
+            dialects: Vec::new(),
+            strict: false,
+            debug: false,
+            input_separator: false,
+            input: &[],
+            code_len: text.len(),
+            checked: 0,
+            warnings: Vec::new(),
+            origins: None,
         }
     }
 
-    fn mct(char: &u8, size: usize) -> TokenType {
+    /// Makes token spans point to `origins[i]` for byte `i` of the text,
+    /// for text that doesn't come straight from a file, like the output of the preprocessor.
+    pub fn with_origins(mut self, origins: &'a [Span]) -> Lexer<'a> {
+        self.origins = Some(origins);
+        self
+    }
+
+    /// Skips comments written in any of `styles`.
+    pub fn with_comments(mut self, styles: &[CommentStyle]) -> Lexer<'a> {
+        self.comments = styles.to_vec();
+        self
+    }
+
+    /// Recognizes the commands added by each of `dialects`.
+    pub fn with_dialects(mut self, dialects: &[Dialect]) -> Lexer<'a> {
+        self.dialects = dialects.to_vec();
+        self
+    }
+
+    /// Warns about command characters that look like they are part of prose.
+    pub fn with_strict(mut self, strict: bool) -> Lexer<'a> {
+        self.strict = strict;
+        self
+    }
+
+    /// Recognizes `#` as the debug command.
+    /// `#` comments take precedence over it.
+    pub fn with_debug(mut self, debug: bool) -> Lexer<'a> {
+        self.debug = debug;
+        self
+    }
+
+    /// Stops lexing at the first `!` outside a comment,
+    /// keeping the rest of the text as input for the program.
+    /// This takes precedence over `!` from Extended Brainfuck Type I.
+    pub fn with_input_separator(mut self, input_separator: bool) -> Lexer<'a> {
+        self.input_separator = input_separator;
+        self
+    }
+
+    /// Returns the text after the input separator found by the last call to `lex`.
+    pub fn get_input(&self) -> &'a [u8] {
+        self.input
+    }
+
+    /// Returns how much of the text is code, which is all of it unless an input separator was found.
+    pub fn get_code_len(&self) -> usize {
+        self.code_len
+    }
+
+    /// Returns the warnings found by the last call to `lex`.
+    pub fn get_warnings(&self) -> &[Diagnostic] {
+        &self.warnings
+    }
+
+    fn mct(char: u8, size: u32) -> TokenType {
         match char {
             b'+' => TokenType::Inc(size),
             b'-' => TokenType::Dec(size),
@@ -87,51 +247,227 @@ impl Lexer {
         }
     }
 
+    /// Returns the token for a command that is always a single byte.
+    fn single(&self, ch: u8) -> Option<TokenType> {
+        let core = match ch {
+            b'[' => Some(TokenType::BracketOpen),
+            b']' => Some(TokenType::BracketClose),
+            b'.' => Some(TokenType::PutChar),
+            b',' => Some(TokenType::GetChar),
+            _ => None,
+        };
+
+        if core.is_some() {
+            return core;
+        }
+
+        if self.debug && ch == b'#' {
+            return Some(TokenType::Debug);
+        }
+
+        if self.dialects.contains(&Dialect::Ebf1) {
+            let ebf1 = match ch {
+                b'@' => Some(TokenType::End),
+                b'$' => Some(TokenType::Store),
+                b'!' => Some(TokenType::Fetch),
+                b'}' => Some(TokenType::ShiftRight),
+                b'{' => Some(TokenType::ShiftLeft),
+                b'~' => Some(TokenType::Not),
+                b'^' => Some(TokenType::Xor),
+                b'&' => Some(TokenType::And),
+                b'|' => Some(TokenType::Or),
+                _ => None,
+            };
+
+            if ebf1.is_some() {
+                return ebf1;
+            }
+        }
+
+        if self.dialects.contains(&Dialect::Pbrain) {
+            let pbrain = match ch {
+                b'(' => Some(TokenType::ProcOpen),
+                b')' => Some(TokenType::ProcClose),
+                b':' => Some(TokenType::Call),
+                _ => None,
+            };
+
+            if pbrain.is_some() {
+                return pbrain;
+            }
+        }
+
+        if self.dialects.contains(&Dialect::Brainfork) && ch == b'Y' {
+            return Some(TokenType::Fork);
+        }
+
+        None
+    }
+
+    fn is_command(&self, ch: u8) -> bool {
+        matches!(ch, b'+' | b'-' | b'<' | b'>') || self.single(ch).is_some()
+    }
+
+    /// Steps past the current byte, moving to the next line if it was a newline.
     fn adv(&mut self) {
+        match self.text.get(self.idx) {
+            Some(b'\n') => {
+                self.ln += 1;
+                self.col = 1;
+            }
+            // UTF-8 continuation bytes belong to the character before them.
+            Some(ch) if self.utf8 && (ch & 0xc0) == 0x80 => (),
+            _ => self.col += 1,
+        }
+
         self.idx += 1;
-        self.col += 1;
+    }
+
+    /// Returns the span of the bytes from `start` to `end`,
+    /// `ln` and `col` being the position of `start`.
+    fn span_between(&self, start: usize, end: usize, ln: usize, col: usize) -> Span {
+        match self.origins {
+            Some(origins) => origins[start].join(origins[end.max(start + 1) - 1]),
+            None => Span::new(self.file, start, end, ln, col),
+        }
+    }
+
+    /// Returns the span from `start` up to the current position.
+    fn span_from(&self, start: usize, ln: usize, col: usize) -> Span {
+        self.span_between(start, self.idx, ln, col)
+    }
+
+    /// Returns the length of the comment starting at the current position, if there is one.
+    fn comment_len(&self, at_start: bool) -> Option<usize> {
+        let rest = &self.text[self.idx..];
+
+        let line_comment = (self.comments.contains(&CommentStyle::Hash) && rest.starts_with(b"#"))
+            || (self.comments.contains(&CommentStyle::Slash) && rest.starts_with(b"//"));
+
+        if line_comment {
+            return Some(
+                rest.iter()
+                    .position(|ch| *ch == b'\n')
+                    .unwrap_or(rest.len()),
+            );
+        }
+
+        if at_start && self.comments.contains(&CommentStyle::Loop) && rest.starts_with(b"[") {
+            let mut depth = 0;
+
+            for (i, ch) in rest.iter().enumerate() {
+                match ch {
+                    b'[' => depth += 1,
+                    b']' => depth -= 1,
+                    _ => continue,
+                }
+
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// In strict mode, warns if the word starting at the current position
+    /// mixes letters with command characters, like the `.` and `,` in "e.g., foo".
+    fn check_prose(&mut self) {
+        if !self.strict || self.idx < self.checked {
+            return;
+        }
+
+        let rest = &self.text[self.idx..];
+        let len = rest
+            .iter()
+            .position(|ch| ch.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+
+        self.checked = self.idx + len.max(1);
+
+        if !word.iter().any(|ch| ch.is_ascii_alphabetic()) {
+            return;
+        }
 
-        if try_index(&self.text, self.idx).is_some_and(|ch| *ch == b'\n') {
-            self.ln += 1;
-            self.col = 1;
+        let mut commands: Vec<String> = Vec::new();
+        for ch in word.iter().filter(|ch| self.is_command(**ch)) {
+            let ch = format!("'{}'", *ch as char);
+            if !commands.contains(&ch) {
+                commands.push(ch);
+            }
+        }
+
+        if commands.is_empty() {
+            return;
         }
+
+        let span = self.span_between(self.idx, self.idx + len, self.ln, self.col);
+
+        self.warnings.push(
+            Diagnostic::warning("command characters in what looks like prose")
+                .with_span(span, &format!("{} will be compiled", commands.join(", ")))
+                .with_note("reword the comment, or move it into a comment with --comments"),
+        );
     }
 
     pub fn lex(&mut self) -> Vec<Token> {
         let mut tokens: Vec<Token> = Vec::new();
 
-        let text_clone = self.text.clone();
+        // The single-byte commands, looked up once instead of for every byte.
+        let commands: Vec<Option<TokenType>> = (0..=u8::MAX).map(|ch| self.single(ch)).collect();
 
-        while let Some(ch) = try_index(&text_clone, self.idx) {
-            match ch {
-                b'+' | b'-' | b'<' | b'>' => {
-                    let mut size: usize = 0;
+        while let Some(&ch) = self.text.get(self.idx) {
+            let (start, ln, col) = (self.idx, self.ln, self.col);
 
-                    while try_index(&text_clone, self.idx).is_some_and(|c| c == ch) {
-                        size += 1;
+            if !self.comments.is_empty() {
+                if let Some(len) = self.comment_len(tokens.is_empty()) {
+                    for _ in 0..len {
                         self.adv();
                     }
-
-                    tokens.push(Token::new(Lexer::mct(ch, size), self.col, self.ln));
+                    continue;
                 }
-                b'[' => {
-                    tokens.push(Token::new(TokenType::BracketOpen, self.col, self.ln));
-                    self.adv();
-                }
-                b']' => {
-                    tokens.push(Token::new(TokenType::BracketClose, self.col, self.ln));
-                    self.adv();
-                }
-                b'.' => {
-                    tokens.push(Token::new(TokenType::PutChar, self.col, self.ln));
-                    self.adv();
+            }
+
+            if self.input_separator && ch == b'!' {
+                self.input = &self.text[self.idx + 1..];
+                self.code_len = self.idx;
+                break;
+            }
+
+            if self.strict && !ch.is_ascii_whitespace() {
+                self.check_prose();
+            }
+
+            let typ = match ch {
+                b'+' | b'-' | b'<' | b'>' => {
+                    let size = self.text[self.idx..]
+                        .iter()
+                        .take(u32::MAX as usize)
+                        .take_while(|c| **c == ch)
+                        .count();
+
+                    // These commands are never newlines or part of a wider character,
+                    // so the run only moves along the line.
+                    self.idx += size;
+                    self.col += size;
+
+                    Lexer::mct(ch, size as u32)
                 }
-                b',' => {
-                    tokens.push(Token::new(TokenType::GetChar, self.col, self.ln));
+                _ => {
+                    let typ = commands[ch as usize];
+
                     self.adv();
+
+                    match typ {
+                        Some(typ) => typ,
+                        None => continue,
+                    }
                 }
-                _ => self.adv(),
-            }
+            };
+
+            tokens.push(Token::new(typ, self.span_from(start, ln, col)));
         }
 
         tokens
@@ -0,0 +1,292 @@
+use crate::{
+    diagnostic::Diagnostic,
+    lexer::{Token, TokenType},
+};
+
+/// A check for code that is valid but almost certainly not what was meant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lint {
+    /// Runs that undo each other, like `+-` or `<>`.
+    CancellingOps,
+    /// Loops that can never run, because they start where the cell is known to be zero.
+    DeadLoop,
+    /// `[]` on a cell that may be nonzero, which never ends.
+    InfiniteLoop,
+    /// `<` that moves before the start of the tape.
+    TapeUnderflow,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [
+        Lint::CancellingOps,
+        Lint::DeadLoop,
+        Lint::InfiniteLoop,
+        Lint::TapeUnderflow,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::CancellingOps => "cancelling-ops",
+            Lint::DeadLoop => "dead-loop",
+            Lint::InfiniteLoop => "infinite-loop",
+            Lint::TapeUnderflow => "tape-underflow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|l| l.name() == name)
+    }
+
+    /// Whether the lint warns unless it is allowed.
+    /// Dead loops don't, since a loop at the start of a program is the usual way to write a comment.
+    pub fn is_default(&self) -> bool {
+        *self != Lint::DeadLoop
+    }
+}
+
+/// Checks a token stream with matched brackets for the lints that are enabled.
+pub struct Linter {
+    enabled: Vec<Lint>,
+}
+
+impl Linter {
+    /// Creates a linter with the default lints enabled.
+    pub fn new() -> Linter {
+        Linter {
+            enabled: Lint::ALL.into_iter().filter(Lint::is_default).collect(),
+        }
+    }
+
+    /// Turns a lint on (`-W`) or off (`-A`).
+    pub fn with_lint(mut self, lint: Lint, enabled: bool) -> Linter {
+        self.enabled.retain(|l| *l != lint);
+
+        if enabled {
+            self.enabled.push(lint);
+        }
+
+        self
+    }
+
+    fn warn(&self, lint: Lint, diags: &mut Vec<Diagnostic>, diag: Diagnostic) {
+        if self.enabled.contains(&lint) {
+            diags.push(diag.with_note(&format!(
+                "this is the '{}' lint, pass '-A {}' to allow it",
+                lint.name(),
+                lint.name()
+            )));
+        }
+    }
+
+    pub fn check(&self, tokens: &[Token]) -> Vec<Diagnostic> {
+        let mut diags: Vec<Diagnostic> = Vec::new();
+
+        // Where the pointer is relative to the start of the tape, while that is known.
+        let mut pos: Option<i64> = Some(0);
+
+        // For each open loop or procedure, where the pointer was when it opened.
+        let mut stack: Vec<Option<i64>> = Vec::new();
+
+        for (i, t) in tokens.iter().enumerate() {
+            let span = t.get_span();
+            let prev = i.checked_sub(1).map(|i| &tokens[i]);
+            let next = tokens.get(i + 1);
+
+            match t.get_typ() {
+                TokenType::Inc(_)
+                | TokenType::Dec(_)
+                | TokenType::Left(_)
+                | TokenType::Right(_) => {
+                    if let Some(prev) = prev {
+                        if cancels(prev.get_typ(), t.get_typ()) {
+                            self.warn(
+                                Lint::CancellingOps,
+                                &mut diags,
+                                Diagnostic::warning("these commands cancel each other out")
+                                    .with_span(prev.get_span().join(span), "this does nothing")
+                                    .with_note("remove both runs, or just the difference"),
+                            );
+                        }
+                    }
+                }
+                _ => (),
+            }
+
+            match t.get_typ() {
+                TokenType::Right(n) => pos = pos.map(|p| p + *n as i64),
+                TokenType::Left(n) => {
+                    pos = pos.map(|p| p - *n as i64);
+
+                    if pos.is_some_and(|p| p < 0) {
+                        self.warn(
+                            Lint::TapeUnderflow,
+                            &mut diags,
+                            Diagnostic::warning("moves before the start of the tape").with_span(
+                                span,
+                                &format!("the pointer is at {} here", pos.unwrap()),
+                            ),
+                        );
+
+                        // Only the first move past the start is worth reporting.
+                        pos = None;
+                    }
+                }
+                TokenType::BracketOpen => {
+                    let known_zero = match prev {
+                        None => true,
+                        Some(prev) => *prev.get_typ() == TokenType::BracketClose,
+                    };
+
+                    if known_zero {
+                        let reason = if prev.is_none() {
+                            "every cell is zero when the program starts"
+                        } else {
+                            "the cell is always zero after a loop ends"
+                        };
+
+                        self.warn(
+                            Lint::DeadLoop,
+                            &mut diags,
+                            Diagnostic::warning("this loop never runs")
+                                .with_span(span, "the cell is zero here")
+                                .with_note(reason),
+                        );
+                    } else if next.is_some_and(|n| *n.get_typ() == TokenType::BracketClose) {
+                        self.warn(
+                            Lint::InfiniteLoop,
+                            &mut diags,
+                            Diagnostic::warning("this loop never ends if it runs")
+                                .with_span(
+                                    span.join(next.unwrap().get_span()),
+                                    "nothing changes the cell",
+                                )
+                                .with_note("use '[-]' to clear the cell"),
+                        );
+                    }
+
+                    stack.push(pos);
+                }
+                TokenType::BracketClose => {
+                    // The pointer is only known after the loop if every iteration
+                    // ends where it started.
+                    let open = stack.pop().flatten();
+
+                    if open != pos {
+                        pos = None;
+                    }
+                }
+                TokenType::ProcOpen => {
+                    // The body of a procedure runs wherever it is called from.
+                    stack.push(pos);
+                    pos = None;
+                }
+                TokenType::ProcClose => pos = stack.pop().flatten(),
+                TokenType::Call => pos = None,
+                _ => (),
+            }
+        }
+
+        diags
+    }
+}
+
+/// Whether `b` undoes `a`, like `-` after `+`.
+fn cancels(a: &TokenType, b: &TokenType) -> bool {
+    matches!(
+        (a, b),
+        (TokenType::Inc(_), TokenType::Dec(_))
+            | (TokenType::Dec(_), TokenType::Inc(_))
+            | (TokenType::Left(_), TokenType::Right(_))
+            | (TokenType::Right(_), TokenType::Left(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Dialect, Lexer};
+
+    /// Returns the messages of the warnings `linter` gives for `code`.
+    fn check(linter: &Linter, code: &str) -> Vec<String> {
+        let tokens = Lexer::new(code.as_bytes(), 0)
+            .with_dialects(&[Dialect::Pbrain])
+            .lex();
+
+        linter
+            .check(&tokens)
+            .iter()
+            .map(|d| d.get_msg().to_string())
+            .collect()
+    }
+
+    fn only(lint: Lint) -> Linter {
+        Lint::ALL
+            .into_iter()
+            .fold(Linter::new(), |linter, l| linter.with_lint(l, l == lint))
+    }
+
+    #[test]
+    fn cancelling_ops() {
+        let linter = only(Lint::CancellingOps);
+        let warning = "these commands cancel each other out";
+
+        assert_eq!(check(&linter, "++--"), [warning]);
+        assert_eq!(check(&linter, ">><"), [warning]);
+        assert!(check(&linter, "+>-<").is_empty());
+        assert!(check(&linter, "+.-").is_empty());
+    }
+
+    #[test]
+    fn dead_loop() {
+        let linter = only(Lint::DeadLoop);
+        let warning = "this loop never runs";
+
+        assert_eq!(check(&linter, "[comment] +[-][.]"), [warning, warning]);
+        assert!(check(&linter, "+[-]>[-]").is_empty());
+        assert!(check(&linter, ",[.,]").is_empty());
+    }
+
+    #[test]
+    fn infinite_loop() {
+        let linter = only(Lint::InfiniteLoop);
+        let warning = "this loop never ends if it runs";
+
+        assert_eq!(check(&linter, ",[]"), [warning]);
+        assert!(check(&linter, ",[-]").is_empty());
+        // A loop that can't run can't run forever either.
+        assert!(check(&linter, "[]").is_empty());
+    }
+
+    #[test]
+    fn tape_underflow() {
+        let linter = only(Lint::TapeUnderflow);
+        let warning = "moves before the start of the tape";
+
+        assert_eq!(check(&linter, "><<<"), [warning]);
+        assert!(check(&linter, ">><<").is_empty());
+        // The pointer is unknown after a loop that moves it.
+        assert!(check(&linter, ">+[<]<<").is_empty());
+        // Procedures run wherever they are called.
+        assert!(check(&linter, "(<):").is_empty());
+    }
+
+    #[test]
+    fn lints_can_be_toggled() {
+        let code = "[comment] <";
+
+        // Dead loops are allowed by default, for the comment at the start.
+        assert_eq!(
+            check(&Linter::new(), code),
+            ["moves before the start of the tape"]
+        );
+
+        let linter = Linter::new()
+            .with_lint(Lint::DeadLoop, true)
+            .with_lint(Lint::TapeUnderflow, false);
+        assert_eq!(check(&linter, code), ["this loop never runs"]);
+
+        assert!(Lint::ALL
+            .iter()
+            .all(|l| Lint::from_name(l.name()) == Some(*l)));
+    }
+}
@@ -1,17 +1,39 @@
-use std::{error::Error, fs, process::exit};
+use std::{
+    error::Error,
+    fs,
+    io::{stderr, stdin, stdout, IsTerminal, Read, Write},
+    path::Path,
+    process::exit,
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use crate::{
     builders::{arm64::builder_linux_arm64, llvm::builder_llvm},
-    generators::{linux_arm64::generator_linux_arm64, llvm::generator_llvm},
-    lexer::Lexer,
+    common::XBFError,
+    diagnostic::{Diagnostic, Renderer},
+    fmt::Formatter,
+    generators::{bf::generator_bf, linux_arm64::generator_linux_arm64, llvm::generator_llvm},
+    ir::passes,
+    lexer::{CommentStyle, Dialect, Lexer},
+    lint::{Lint, Linter},
+    preprocessor::{Expansion, Preprocessor},
+    source::SourceMap,
+    syntax::Syntax,
 };
 
 mod builders;
 mod common;
+mod diagnostic;
+mod fmt;
 mod generators;
+mod ir;
 mod lexer;
+mod lint;
+mod parser;
+mod preprocessor;
+mod source;
+mod syntax;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -21,15 +43,17 @@ struct Args {
     tokens: bool,
 
     /// The amount of memory alloted to the BF program.
-    #[arg(short, long, default_value_t = 30000)]
+    #[arg(short, long, default_value_t = 30000, value_parser = memory_size)]
     mem: usize,
 
     /// The target pair to compile for.
-    /// The available options are 'macos-arm64', 'linux-arm64', and 'llvm'.
+    /// The available options are 'macos-arm64', 'linux-arm64', 'llvm',
+    /// and 'bf' (minimal source with the comments and code that does nothing removed).
     #[arg(short, long, default_value_t = ("macos-arm64").to_string())]
     target: String,
 
     /// The final output file.
+    /// With the 'bf' target, '-' writes to stdout.
     #[arg(short, long, default_value_t = ("a.out").to_string())]
     output: String,
 
@@ -43,49 +67,484 @@ struct Args {
     #[arg(short, long, default_value_t = ("").to_string())]
     linker: String,
 
+    /// When to color diagnostics.
+    /// The available options are 'auto', 'always', and 'never'.
+    #[arg(long, default_value_t = ("auto").to_string(), global = true)]
+    color: String,
+
+    /// Compile the given code as if it were a source file.
+    /// Can be given more than once, and is read after any files.
+    #[arg(
+        short,
+        long = "eval",
+        value_name = "CODE",
+        allow_hyphen_values = true,
+        global = true
+    )]
+    eval: Vec<String>,
+
+    /// Comment styles to recognize, separated by commas.
+    /// The available options are 'hash' ('#' line comments), 'slash' ('//' line comments),
+    /// and 'loop' (a loop at the start of the program).
+    #[arg(long, default_value_t = ("").to_string(), global = true)]
+    comments: String,
+
+    /// Language extensions to enable, separated by commas.
+    /// The available options are 'ebf1' (Extended Brainfuck Type I), 'pbrain' (procedures),
+    /// and 'brainfork' (threads, only for the 'llvm' target).
+    #[arg(long, default_value_t = ("").to_string(), global = true)]
+    dialect: String,
+
+    /// The surface syntax of the sources.
+    /// The available options are 'ook' (Ook!), 'blub' (Blub), or the path to a mapping file
+    /// with a command character and the string that spells it on each line.
+    #[arg(long, value_name = "SYNTAX", global = true)]
+    syntax: Option<String>,
+
+    /// Warn about command characters that look like they are part of prose.
+    #[arg(long, default_value_t = false, global = true)]
+    strict: bool,
+
+    /// Compile '#' into a command that prints the pointer and the cells around it to stderr.
+    #[arg(long, default_value_t = false, global = true)]
+    debug: bool,
+
+    /// Run the child of each Brainfork fork to the end before the parent continues,
+    /// so the output is the same every time.
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
+    /// Treat everything after the first '!' in the sources as input for the program,
+    /// which is read before stdin.
+    #[arg(long, default_value_t = false, global = true)]
+    embedded_input: bool,
+
+    /// Run the macro preprocessor over the sources before lexing them.
+    #[arg(short, long, default_value_t = false, global = true)]
+    preprocess: bool,
+
+    /// Define a name for '#ifdef' sections in the preprocessor.
+    #[arg(short = 'D', long = "define", value_name = "NAME", global = true)]
+    defines: Vec<String>,
+
+    /// Add a directory to search for files included with '#include'.
+    #[arg(short = 'I', long = "include-dir", value_name = "DIR", global = true)]
+    include_dirs: Vec<String>,
+
+    /// The source files to compile, '-' reads from stdin.
     files: Vec<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check the sources for common mistakes instead of compiling them.
+    Lint {
+        /// Warn about a lint, or every lint with 'all'.
+        /// The available lints are 'cancelling-ops', 'dead-loop', 'infinite-loop',
+        /// and 'tape-underflow'. All but 'dead-loop' warn by default,
+        /// since a loop at the start of a program is the usual way to write a comment.
+        #[arg(short = 'W', value_name = "LINT")]
+        warn: Vec<String>,
+
+        /// Allow a lint, or every lint with 'all'.
+        /// Naming a lint takes precedence over 'all'.
+        #[arg(short = 'A', value_name = "LINT")]
+        allow: Vec<String>,
+
+        /// The source files to check, '-' reads from stdin.
+        files: Vec<String>,
+    },
+
+    /// Rewrite the source files in a canonical layout.
+    /// Code from stdin or '--eval' is written to stdout instead.
+    Fmt {
+        /// Don't write anything, but list the sources that aren't formatted and fail if there are any.
+        #[arg(long, default_value_t = false)]
+        check: bool,
+
+        /// The line width to wrap code at.
+        #[arg(long, default_value_t = 80)]
+        width: usize,
+
+        /// The number of spaces to indent each level of loops and procedures by.
+        #[arg(long, default_value_t = 4)]
+        indent: usize,
+
+        /// The source files to format, '-' reads from stdin.
+        files: Vec<String>,
+    },
+}
+
+/// Parses the memory size, which needs at least one cell.
+fn memory_size(arg: &str) -> Result<usize, String> {
+    match arg.parse::<usize>() {
+        Ok(0) => Err("the tape needs at least one cell".to_string()),
+        Ok(size) => Ok(size),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn report(diags: &[Diagnostic], sources: &SourceMap, color: bool) -> Result<(), Box<dyn Error>> {
+    let renderer = Renderer::new(sources, color);
+
+    for diag in diags {
+        eprintln!("{}", renderer.render(diag)?);
+    }
+
+    eprint!("{}", renderer.summary(diags)?);
+
+    Ok(())
+}
+
+/// Builds a linter from the names given to `-W` and `-A`.
+fn linter(warn: &[String], allow: &[String]) -> Linter {
+    let mut linter = Linter::new();
+
+    // 'all' goes first, so that naming a single lint overrides it.
+    let mut levels: Vec<(&String, bool)> = Vec::new();
+    levels.extend(warn.iter().filter(|n| *n == "all").map(|n| (n, true)));
+    levels.extend(allow.iter().filter(|n| *n == "all").map(|n| (n, false)));
+    levels.extend(warn.iter().filter(|n| *n != "all").map(|n| (n, true)));
+    levels.extend(allow.iter().filter(|n| *n != "all").map(|n| (n, false)));
+
+    for (name, enabled) in levels {
+        let lints = match Lint::from_name(name) {
+            Some(lint) => vec![lint],
+            None if name == "all" => Lint::ALL.to_vec(),
+            None => {
+                eprintln!("unknown lint '{}'", name);
+                exit(1);
+            }
+        };
+
+        for lint in lints {
+            linter = linter.with_lint(lint, enabled);
+        }
+    }
+
+    linter
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
-    if args.files.len() == 0 {
-        eprintln!("");
+    let files = match &args.command {
+        Some(Command::Lint { files, .. }) | Some(Command::Fmt { files, .. }) => files,
+        None => &args.files,
+    };
+
+    if files.is_empty() && args.eval.is_empty() {
+        eprintln!("no input files");
         exit(1);
     }
 
-    let mut acc = String::new();
+    let color = match args.color.as_str() {
+        "auto" => stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        "always" => true,
+        "never" => false,
+        _ => {
+            eprintln!("unknown color option '{}'", args.color);
+            exit(1);
+        }
+    };
+
+    let mut comments: Vec<CommentStyle> = Vec::new();
+
+    for name in args.comments.split(',').filter(|s| !s.is_empty()) {
+        match CommentStyle::from_name(name) {
+            Some(style) => comments.push(style),
+            None => {
+                eprintln!("unknown comment style '{}'", name);
+                exit(1);
+            }
+        }
+    }
+
+    let mut dialects: Vec<Dialect> = Vec::new();
+
+    for name in args.dialect.split(',').filter(|s| !s.is_empty()) {
+        match Dialect::from_name(name) {
+            Some(dialect) => dialects.push(dialect),
+            None => {
+                eprintln!("unknown dialect '{}'", name);
+                exit(1);
+            }
+        }
+    }
+
+    let mut sources = SourceMap::new();
+
+    let syntax = match &args.syntax {
+        Some(name) => match Syntax::from_name(name) {
+            Some(syntax) => Some(syntax),
+            None => {
+                let content = match fs::read(name) {
+                    Ok(content) => content,
+                    Err(e) => {
+                        eprintln!("{}: {}", name, e);
+                        exit(1);
+                    }
+                };
+
+                let id = match sources.add(name, content) {
+                    Ok(id) => id,
+                    Err(diag) => {
+                        report(&[diag], &sources, color)?;
+                        exit(1);
+                    }
+                };
+
+                match Syntax::parse(&sources, id) {
+                    Ok(syntax) => Some(syntax),
+                    Err(e) => {
+                        report(e.get_diagnostics(), &sources, color)?;
+                        exit(1);
+                    }
+                }
+            }
+        },
+        None => None,
+    };
+
+    let mut tokens = Vec::new();
+    let mut warnings: Vec<Diagnostic> = Vec::new();
+    let mut input: Vec<u8> = Vec::new();
+    let mut preprocessor = Preprocessor::new(&args.defines, &args.include_dirs);
+
+    let mut inputs: Vec<(String, Vec<u8>)> = Vec::new();
 
-    for file in &args.files {
-        let content = match fs::read_to_string(file) {
-            Ok(s) => s,
+    for file in files {
+        let content = if file == "-" {
+            let mut buf = Vec::new();
+            stdin().read_to_end(&mut buf).map(|_| buf)
+        } else {
+            fs::read(file)
+        };
+
+        match content {
+            Ok(content) => inputs.push((file.clone(), content)),
             Err(e) => {
-                eprintln!("{}", e);
+                eprintln!("{}: {}", file, e);
                 exit(1);
             }
+        }
+    }
+
+    for (i, code) in args.eval.iter().enumerate() {
+        let name = if args.eval.len() == 1 {
+            "<eval>".to_string()
+        } else {
+            format!("<eval {}>", i + 1)
         };
 
-        acc.push_str(&content);
+        inputs.push((name, code.clone().into_bytes()));
     }
 
-    let mut lexer = Lexer::new(&acc);
+    if let Some(Command::Fmt {
+        check,
+        width,
+        indent,
+        ..
+    }) = args.command
+    {
+        // The formatter lays out the commands as they are written.
+        if args.preprocess || syntax.is_some() {
+            eprintln!("sources can't be formatted with '--preprocess' or '--syntax'");
+            exit(1);
+        }
+
+        let formatter = Formatter::new(width, indent);
+        let mut unformatted = false;
+
+        for (i, (name, content)) in inputs.into_iter().enumerate() {
+            let is_file = i < files.len() && name != "-";
+            let name = if name == "-" { "<stdin>" } else { &name };
+
+            let id = match sources.add(name, content) {
+                Ok(id) => id,
+                Err(diag) => {
+                    report(&[diag], &sources, color)?;
+                    exit(1);
+                }
+            };
+            let text = sources.get(id).get_text();
+
+            if i > 0 {
+                comments.retain(|c| *c != CommentStyle::Loop);
+            }
+
+            let mut lexer = Lexer::new(text, id)
+                .with_comments(&comments)
+                .with_dialects(&dialects)
+                .with_debug(args.debug)
+                .with_input_separator(args.embedded_input);
+
+            let tokens = lexer.lex();
+
+            // Blocks are laid out by their brackets, so those have to match.
+            if let Err(e) = parser::Parser::new(tokens.clone(), &sources).parse() {
+                report(e.get_diagnostics(), &sources, color)?;
+                exit(1);
+            }
+
+            // Embedded input is kept as it is.
+            let code_len = lexer.get_code_len();
+            let mut formatted = formatter.format(&text[..code_len], &tokens);
+            formatted.extend_from_slice(&text[code_len..]);
+
+            if check {
+                if formatted != text {
+                    eprintln!("{} is not formatted", name);
+                    unformatted = true;
+                }
+            } else if !is_file {
+                stdout().write_all(&formatted)?;
+            } else if formatted != text {
+                fs::write(name, formatted)?;
+            }
+        }
+
+        if unformatted {
+            exit(1);
+        }
+
+        return Ok(());
+    }
+
+    for (name, content) in inputs {
+        let name = if name == "-" { "<stdin>" } else { &name };
+
+        let id = match sources.add(name, content) {
+            Ok(id) => id,
+            Err(diag) => {
+                report(&[diag], &sources, color)?;
+                exit(1);
+            }
+        };
+
+        // Only the start of the whole program can hold a comment loop.
+        if !tokens.is_empty() {
+            comments.retain(|c| *c != CommentStyle::Loop);
+        }
+
+        let expansion = if args.preprocess {
+            match preprocessor.run(&mut sources, id) {
+                Ok(expansion) => Some(expansion),
+                Err(e) => {
+                    report(e.get_diagnostics(), &sources, color)?;
+                    exit(1);
+                }
+            }
+        } else {
+            None
+        };
+
+        // Other syntaxes are translated into commands after preprocessing.
+        let expansion = match &syntax {
+            Some(syntax) => Some(syntax.translate(
+                &expansion.unwrap_or_else(|| Expansion::of_file(id, sources.get(id).get_text())),
+            )),
+            None => expansion,
+        };
 
-    let tokens = lexer.lex();
+        let mut lexer = match &expansion {
+            Some(expansion) => Lexer::new(&expansion.text, id).with_origins(&expansion.origins),
+            None => Lexer::new(sources.get(id).get_text(), id),
+        }
+        .with_comments(&comments)
+        .with_dialects(&dialects)
+        .with_strict(args.strict)
+        .with_debug(args.debug)
+        .with_input_separator(args.embedded_input);
+
+        tokens.append(&mut lexer.lex());
+        warnings.extend_from_slice(lexer.get_warnings());
+        input.extend_from_slice(lexer.get_input());
+    }
 
     if args.tokens {
         for t in &tokens {
-            println!("{}", t);
+            println!("{} {}", sources.location(t.get_span()), t);
+        }
+    }
+
+    // Lints run on the tokens, but are only reported once the brackets are known to match.
+    let lints = match &args.command {
+        Some(Command::Lint { warn, allow, .. }) => linter(warn, allow).check(&tokens),
+        _ => Vec::new(),
+    };
+
+    let program = match parser::Parser::new(tokens, &sources).parse() {
+        Ok(program) => program,
+        Err(e) => {
+            warnings.extend_from_slice(e.get_diagnostics());
+            report(&warnings, &sources, color)?;
+            exit(1);
         }
+    };
+
+    if let Some(Command::Lint { .. }) = args.command {
+        warnings.extend(lints);
+
+        if !warnings.is_empty() {
+            report(&warnings, &sources, color)?;
+        }
+
+        return Ok(());
     }
 
+    if !warnings.is_empty() {
+        report(&warnings, &sources, color)?;
+    }
+
+    // The 'bf' target is source, so there is nothing to build.
+    if args.target == "bf" {
+        // Extended Brainfuck uses '!' as a command, so it can't also start the input.
+        if args.embedded_input && dialects.contains(&Dialect::Ebf1) {
+            eprintln!("the 'bf' target can't embed input in programs using '--dialect ebf1'");
+            exit(1);
+        }
+
+        let code = generator_bf(passes::simplify(ir::lower(&program)), &input)?;
+
+        if args.output == "-" {
+            stdout().write_all(&code)?;
+        } else {
+            fs::write(&args.output, code)?;
+        }
+
+        return Ok(());
+    }
+
+    let program = passes::optimize(ir::lower(&program));
+
+    let module_name = Path::new(&args.output)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("xbf");
+
     let asm = match args.target.as_str() {
-        "macos-arm64" | "linux-arm64" => generator_linux_arm64(tokens, args.mem),
-        "llvm" => generator_llvm(tokens, args.mem, &args.files[0]),
+        "macos-arm64" | "linux-arm64" => generator_linux_arm64(program, args.mem, &input),
+        "llvm" => generator_llvm(program, args.mem, &input, args.deterministic, module_name),
         _ => {
             eprintln!("unknown target '{}'", args.target);
             exit(1);
         }
-    }?;
+    };
+
+    let asm = match asm {
+        Ok(asm) => asm,
+        Err(e) => match e.downcast_ref::<XBFError>() {
+            Some(e) => {
+                report(e.get_diagnostics(), &sources, color)?;
+                exit(1);
+            }
+            None => return Err(e),
+        },
+    };
 
     let assembler = if args.linker.is_empty() {
         match args.target.as_str() {
@@ -0,0 +1,408 @@
+use std::fmt::{Debug, Display};
+
+use crate::{
+    common::{Span, XBFError},
+    diagnostic::Diagnostic,
+    lexer::{Token, TokenType},
+    source::SourceMap,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeType {
+    Inc(usize),
+    Dec(usize),
+    Left(usize),
+    Right(usize),
+    Loop(Vec<Node>),
+    /// A pbrain procedure, defined under the number in the current cell when it is reached.
+    Procedure(Vec<Node>),
+    Call,
+    /// Forks the program: the current cell of the parent is set to 0,
+    /// and the child gets a copy of the tape with the cell to the right set to 1.
+    Fork,
+    /// Prints the pointer and the cells around it to stderr.
+    Debug,
+    PutChar,
+    GetChar,
+    End,
+    Store,
+    Fetch,
+    ShiftRight,
+    ShiftLeft,
+    Not,
+    Xor,
+    And,
+    Or,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Node {
+    typ: NodeType,
+    span: Span,
+}
+
+impl Node {
+    pub fn new(typ: NodeType, span: Span) -> Node {
+        Node { typ, span }
+    }
+
+    pub fn get_typ(&self) -> &NodeType {
+        &self.typ
+    }
+
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+}
+
+impl Debug for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<{:?}, {}, {}, {}..{}>",
+            self.typ, self.span.col, self.span.ln, self.span.start, self.span.end
+        )
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        (self as &dyn Debug).fmt(f)
+    }
+}
+
+/// Measures the indentation of a line, counting a tab as four columns.
+/// Returns `None` for blank lines, which don't belong to any block.
+fn indent(line: &str) -> Option<usize> {
+    if line.trim().is_empty() {
+        return None;
+    }
+
+    Some(
+        line.chars()
+            .take_while(|c| c.is_whitespace())
+            .map(|c| if c == '\t' { 4 } else { 1 })
+            .sum(),
+    )
+}
+
+/// Guesses where the ']' for the unclosed '[' at `open` was meant to go:
+/// after the block of lines indented under the line of the '[',
+/// or at the end of that line if nothing is indented under it.
+fn guess_close(sources: &SourceMap, open: Span) -> Span {
+    let file = sources.get(open.file);
+    let open_ln = open.ln as usize;
+    let base = indent(&file.get_line(open_ln)).unwrap_or(0);
+
+    let mut last = open_ln;
+
+    for ln in open_ln + 1..=file.line_count() {
+        match indent(&file.get_line(ln)) {
+            None => continue,
+            Some(i) if i > base => last = ln,
+            Some(_) => break,
+        }
+    }
+
+    sources.span_at(
+        open.file,
+        last,
+        file.get_line(last).trim_end().chars().count() + 1,
+    )
+}
+
+/// Guesses where the '[' for the unmatched ']' at `close` was meant to go:
+/// at the end of the line that the block above the ']' is indented under,
+/// or at the start of the code on the line of the ']' if there is no such block.
+fn guess_open(sources: &SourceMap, close: Span) -> Span {
+    let file = sources.get(close.file);
+    let close_ln = close.ln as usize;
+    let base = indent(&file.get_line(close_ln)).unwrap_or(0);
+
+    let mut block_start = None;
+
+    for ln in (1..close_ln).rev() {
+        match indent(&file.get_line(ln)) {
+            None => continue,
+            Some(i) if i > base => block_start = Some(ln),
+            Some(_) => {
+                if block_start.is_some() {
+                    let col = file.get_line(ln).trim_end().chars().count() + 1;
+                    return sources.span_at(close.file, ln, col);
+                }
+                break;
+            }
+        }
+    }
+
+    let ln = block_start.unwrap_or(close_ln);
+    let col = indent(&file.get_line(ln)).map_or(1, |_| {
+        file.get_line(ln)
+            .chars()
+            .take_while(|c| c.is_whitespace())
+            .count()
+            + 1
+    });
+
+    sources.span_at(close.file, ln, col)
+}
+
+/// The kinds of bracketed blocks a program can nest.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Loop,
+    Procedure,
+}
+
+impl Block {
+    fn open(&self) -> char {
+        match self {
+            Block::Loop => '[',
+            Block::Procedure => '(',
+        }
+    }
+
+    fn close(&self) -> char {
+        match self {
+            Block::Loop => ']',
+            Block::Procedure => ')',
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Block::Loop => "loop",
+            Block::Procedure => "procedure",
+        }
+    }
+}
+
+/// An open block while parsing: its kind and the span of its opening bracket,
+/// or `None` for the program itself, and the body collected so far.
+type Frame = (Option<(Block, Span)>, Vec<Node>);
+
+/// Turns the flat token stream from the lexer into a program tree,
+/// where every loop and procedure owns the nodes of its body.
+pub struct Parser<'a> {
+    tokens: Vec<Token>,
+    sources: &'a SourceMap,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Vec<Token>, sources: &'a SourceMap) -> Parser<'a> {
+        Parser { tokens, sources }
+    }
+
+    /// Builds the program tree, reporting every unmatched bracket at once.
+    pub fn parse(&mut self) -> Result<Vec<Node>, XBFError> {
+        // The program itself lives at the bottom of the stack.
+        let mut stack: Vec<Frame> = vec![(None, Vec::new())];
+
+        let mut diags: Vec<Diagnostic> = Vec::new();
+
+        for t in &self.tokens {
+            let span = t.get_span();
+
+            let typ = match t.get_typ() {
+                TokenType::Inc(n) => NodeType::Inc(*n as usize),
+                TokenType::Dec(n) => NodeType::Dec(*n as usize),
+                TokenType::Left(n) => NodeType::Left(*n as usize),
+                TokenType::Right(n) => NodeType::Right(*n as usize),
+                TokenType::PutChar => NodeType::PutChar,
+                TokenType::GetChar => NodeType::GetChar,
+                TokenType::End => NodeType::End,
+                TokenType::Store => NodeType::Store,
+                TokenType::Fetch => NodeType::Fetch,
+                TokenType::ShiftRight => NodeType::ShiftRight,
+                TokenType::ShiftLeft => NodeType::ShiftLeft,
+                TokenType::Not => NodeType::Not,
+                TokenType::Xor => NodeType::Xor,
+                TokenType::And => NodeType::And,
+                TokenType::Or => NodeType::Or,
+                TokenType::Call => NodeType::Call,
+                TokenType::Debug => NodeType::Debug,
+                TokenType::Fork => NodeType::Fork,
+                TokenType::BracketOpen | TokenType::ProcOpen => {
+                    let block = if *t.get_typ() == TokenType::BracketOpen {
+                        Block::Loop
+                    } else {
+                        Block::Procedure
+                    };
+
+                    stack.push((Some((block, span)), Vec::new()));
+                    continue;
+                }
+                TokenType::BracketClose | TokenType::ProcClose => {
+                    let block = if *t.get_typ() == TokenType::BracketClose {
+                        Block::Loop
+                    } else {
+                        Block::Procedure
+                    };
+
+                    // Skip a stray closing bracket so the rest of the program is still checked.
+                    match stack.last().unwrap().0 {
+                        None => {
+                            diags.push(
+                                Diagnostic::error(&format!("unmatched '{}'", block.close()))
+                                    .with_span(
+                                        span,
+                                        &format!("there is no open {} to close", block.name()),
+                                    )
+                                    .with_label(
+                                        guess_open(self.sources, span),
+                                        &format!("perhaps the '{}' belongs here", block.open()),
+                                    ),
+                            );
+                            continue;
+                        }
+                        Some((open_block, open)) if open_block != block => {
+                            diags.push(
+                                Diagnostic::error(&format!("mismatched '{}'", block.close()))
+                                    .with_span(
+                                        span,
+                                        &format!(
+                                            "expected '{}' to close the {} first",
+                                            open_block.close(),
+                                            open_block.name()
+                                        ),
+                                    )
+                                    .with_label(
+                                        open,
+                                        &format!("{} opened here", open_block.name()),
+                                    ),
+                            );
+                            continue;
+                        }
+                        Some(_) => (),
+                    }
+
+                    let (open, body) = stack.pop().unwrap();
+                    let span = open.unwrap().1.join(span);
+
+                    let typ = match block {
+                        Block::Loop => NodeType::Loop(body),
+                        Block::Procedure => NodeType::Procedure(body),
+                    };
+
+                    stack.last_mut().unwrap().1.push(Node::new(typ, span));
+                    continue;
+                }
+            };
+
+            stack.last_mut().unwrap().1.push(Node::new(typ, span));
+        }
+
+        for (open, _) in &stack {
+            if let Some((block, open)) = open {
+                let end = self.tokens.last().unwrap().get_span();
+
+                diags.push(
+                    Diagnostic::error(&format!("unclosed '{}'", block.open()))
+                        .with_span(end, "the program ends here")
+                        .with_label(*open, &format!("{} opened here", block.name()))
+                        .with_label(
+                            guess_close(self.sources, *open),
+                            &format!("perhaps the '{}' belongs here", block.close()),
+                        ),
+                );
+            }
+        }
+
+        if !diags.is_empty() {
+            return Err(XBFError::from(diags));
+        }
+
+        Ok(stack.pop().unwrap().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostic::Renderer,
+        lexer::{Dialect, Lexer},
+    };
+
+    fn source(code: &str) -> SourceMap {
+        let mut sources = SourceMap::new();
+        sources.add("<test>", code.as_bytes().to_vec()).unwrap();
+        sources
+    }
+
+    /// Returns the line and column of a guess for the bracket at `ln` and `col`.
+    fn guess(code: &str, ln: usize, col: usize, guess: fn(&SourceMap, Span) -> Span) -> (u32, u32) {
+        let sources = source(code);
+        let span = guess(&sources, sources.span_at(0, ln, col));
+
+        (span.ln, span.col)
+    }
+
+    fn errors(code: &str) -> Vec<String> {
+        let sources = source(code);
+        let tokens = Lexer::new(sources.get(0).get_text(), 0)
+            .with_dialects(&[Dialect::Pbrain])
+            .lex();
+
+        match Parser::new(tokens, &sources).parse() {
+            Ok(_) => Vec::new(),
+            Err(e) => e
+                .get_diagnostics()
+                .iter()
+                .map(|d| Renderer::new(&sources, false).render(d).unwrap())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn reports_every_unmatched_bracket() {
+        let errors = errors("]\n+[->+<]\n(.]\n+[\n");
+
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors[0].starts_with("error: unmatched ']'\n --> <test>:1:1"));
+        assert!(errors[1].starts_with("error: mismatched ']'\n --> <test>:3:3"));
+        assert!(errors[2].starts_with("error: unclosed '('"));
+        assert!(errors[3].starts_with("error: unclosed '['"));
+        assert!(errors[3].contains("perhaps the ']' belongs here"));
+    }
+
+    #[test]
+    fn guesses_close_after_the_indented_block() {
+        let code = "+[\n    >+[\n        -\n\n        >+\n    <\n.\n";
+
+        // The inner loop holds the lines indented under it, the outer loop all of them.
+        assert_eq!(guess(code, 2, 7, guess_close), (5, 11));
+        assert_eq!(guess(code, 1, 2, guess_close), (6, 6));
+        // With nothing indented under it, the loop ends with its line.
+        assert_eq!(guess(">[-\n.\n", 1, 2, guess_close), (1, 4));
+    }
+
+    #[test]
+    fn guesses_open_before_the_indented_block() {
+        let code = "+\n    >,[\n        <-\n    ]\n]\n";
+
+        // The ']' on line 5 closes the block indented under line 1.
+        assert_eq!(guess(code, 5, 1, guess_open), (1, 2));
+        // With no block above it, the loop starts with the code on its line.
+        assert_eq!(guess("+\n  >-]\n", 2, 5, guess_open), (2, 3));
+    }
+
+    #[test]
+    fn loop_across_files_spans_its_opening() {
+        let mut sources = SourceMap::new();
+        sources.add("a", b"[".to_vec()).unwrap();
+        sources.add("b", b"+]".to_vec()).unwrap();
+
+        let open = Span::new(0, 0, 1, 1, 1);
+        let tokens = vec![
+            Token::new(TokenType::BracketOpen, open),
+            Token::new(TokenType::Inc(1), Span::new(1, 0, 1, 1, 1)),
+            Token::new(TokenType::BracketClose, Span::new(1, 1, 2, 1, 2)),
+        ];
+
+        match Parser::new(tokens, &sources).parse() {
+            Ok(program) => assert_eq!(program[0].get_span(), open),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+}
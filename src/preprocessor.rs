@@ -0,0 +1,938 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    common::{FileId, Span, XBFError},
+    diagnostic::Diagnostic,
+    source::SourceMap,
+};
+
+/// A byte of source and where it came from.
+type Spanned = (u8, Span);
+
+/// Source text where every byte remembers where it came from.
+type Text = Vec<Spanned>;
+
+/// How deep macros may expand inside each other before giving up.
+const MAX_DEPTH: usize = 64;
+
+/// How many bytes a single repeat may expand to.
+const MAX_REPEAT_LEN: usize = 1 << 22;
+
+/// The output of the preprocessor: plain source for the lexer,
+/// plus the span in the original files of every byte in it.
+pub struct Expansion {
+    pub text: Vec<u8>,
+    pub origins: Vec<Span>,
+}
+
+impl Expansion {
+    /// Returns the text of a file unchanged, with every byte pointing back at itself.
+    pub fn of_file(file: FileId, bytes: &[u8]) -> Expansion {
+        let text = spanned(file, bytes);
+
+        Expansion {
+            text: text.iter().map(|(ch, _)| *ch).collect(),
+            origins: text.iter().map(|(_, span)| *span).collect(),
+        }
+    }
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Text,
+    span: Span,
+}
+
+/// Splits a file into bytes that each carry their own one-byte span.
+fn spanned(file: FileId, bytes: &[u8]) -> Text {
+    let utf8 = std::str::from_utf8(bytes).is_ok();
+
+    let (mut ln, mut col) = (1, 1);
+
+    bytes
+        .iter()
+        .enumerate()
+        .map(|(i, ch)| {
+            let span = Span::new(file, i, i + 1, ln, col);
+
+            match ch {
+                b'\n' => {
+                    ln += 1;
+                    col = 1;
+                }
+                ch if utf8 && (ch & 0xc0) == 0x80 => (),
+                _ => col += 1,
+            }
+
+            (*ch, span)
+        })
+        .collect()
+}
+
+fn to_string(text: &[Spanned]) -> String {
+    String::from_utf8_lossy(&text.iter().map(|(ch, _)| *ch).collect::<Vec<u8>>()).into_owned()
+}
+
+fn trim(mut text: &[Spanned]) -> &[Spanned] {
+    while let [(ch, _), rest @ ..] = text {
+        if !ch.is_ascii_whitespace() {
+            break;
+        }
+        text = rest;
+    }
+
+    while let [rest @ .., (ch, _)] = text {
+        if !ch.is_ascii_whitespace() {
+            break;
+        }
+        text = rest;
+    }
+
+    text
+}
+
+/// Returns the span covering all of `text`, or `fallback` if it is empty.
+fn span_of(text: &[Spanned], fallback: Span) -> Span {
+    match (text.first(), text.last()) {
+        (Some((_, first)), Some((_, last))) => first.join(*last),
+        _ => fallback,
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Finds the last `sep` in `text` that isn't nested in braces or parentheses.
+fn rfind_top(text: &[Spanned], sep: u8) -> Option<usize> {
+    let mut depth = 0;
+    let mut found = None;
+
+    for (i, (ch, _)) in text.iter().enumerate() {
+        match ch {
+            b'{' | b'(' => depth += 1,
+            b'}' | b')' => depth -= 1,
+            ch if *ch == sep && depth == 0 => found = Some(i),
+            _ => (),
+        }
+    }
+
+    found
+}
+
+/// Splits `text` on `sep`, ignoring separators nested in braces or parentheses.
+fn split_top(text: &[Spanned], sep: u8) -> Vec<&[Spanned]> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, (ch, _)) in text.iter().enumerate() {
+        match ch {
+            b'{' | b'(' => depth += 1,
+            b'}' | b')' => depth -= 1,
+            ch if *ch == sep && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Splits `text` into a leading identifier and the text after it.
+fn split_ident(text: &[Spanned]) -> (String, &[Spanned]) {
+    let len = text
+        .iter()
+        .position(|(ch, _)| !(ch.is_ascii_alphanumeric() || *ch == b'_'))
+        .unwrap_or(text.len());
+
+    (to_string(&text[..len]), &text[len..])
+}
+
+/// Finds the '}' that closes the '{' at `open`.
+fn matching_brace(text: &[Spanned], open: usize) -> Option<usize> {
+    let mut depth = 0;
+
+    for (i, (ch, _)) in text.iter().enumerate().skip(open) {
+        match ch {
+            b'{' => depth += 1,
+            b'}' => depth -= 1,
+            _ => continue,
+        }
+
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+
+    None
+}
+
+/// An open '#ifdef' or '#ifndef' section.
+struct Cond {
+    span: Span,
+    /// Whether the lines around the section are kept.
+    parent: bool,
+    /// Whether the condition held.
+    taken: bool,
+    in_else: bool,
+}
+
+impl Cond {
+    fn keep(&self) -> bool {
+        self.parent && (self.taken != self.in_else)
+    }
+}
+
+/// Expands macros, repeats and conditional sections before the source reaches the lexer.
+///
+/// Directives are lines starting with `#`:
+///
+/// ```text
+/// #define NAME body          a single line macro, or a flag if the body is empty
+/// #define NAME(a, b) body    a macro with parameters
+/// #macro NAME(a, b)          a macro made of every line up to '#endmacro'
+/// #undef NAME
+/// #ifdef NAME / #ifndef NAME / #else / #endif
+/// #include "path"            the file at `path`, looked up next to the current file
+///                            and then in the include directories
+/// #include <path>            the file at `path`, looked up in the include directories
+/// ```
+///
+/// A file is only ever included once.
+///
+/// Inside the source, `{NAME}` and `{NAME(x, y)}` expand a macro, `{a}` expands
+/// the parameter `a` inside a macro body, and `{code * N}` repeats code N times.
+pub struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    defines: HashSet<String>,
+    include_dirs: Vec<PathBuf>,
+    /// The files currently being preprocessed, outermost first.
+    stack: Vec<PathBuf>,
+    /// The files that have been fully preprocessed.
+    included: HashSet<PathBuf>,
+}
+
+impl Preprocessor {
+    pub fn new(defines: &[String], include_dirs: &[String]) -> Preprocessor {
+        Preprocessor {
+            macros: HashMap::new(),
+            defines: defines.iter().cloned().collect(),
+            include_dirs: include_dirs.iter().map(PathBuf::from).collect(),
+            stack: Vec::new(),
+            included: HashSet::new(),
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.defines.contains(name) || self.macros.contains_key(name)
+    }
+
+    /// Parses the `NAME(a, b) body` part of a '#define' or '#macro' line.
+    fn parse_header<'t>(
+        &self,
+        rest: &'t [Spanned],
+        span: Span,
+    ) -> Result<(String, Vec<String>, &'t [Spanned]), Diagnostic> {
+        let (name, mut rest) = split_ident(trim(rest));
+
+        if !is_ident(&name) {
+            return Err(Diagnostic::error("expected a macro name").with_span(span, ""));
+        }
+
+        let mut params = Vec::new();
+
+        if rest.first().is_some_and(|(ch, _)| *ch == b'(') {
+            let close = match rest.iter().position(|(ch, _)| *ch == b')') {
+                Some(close) => close,
+                None => {
+                    return Err(Diagnostic::error("unclosed parameter list")
+                        .with_span(span_of(rest, span), "expected ')'"))
+                }
+            };
+
+            let list = &rest[1..close];
+
+            for param in split_top(list, b',') {
+                let param = trim(param);
+
+                if trim(list).is_empty() {
+                    break;
+                }
+
+                if !is_ident(&to_string(param)) {
+                    return Err(Diagnostic::error("expected a parameter name")
+                        .with_span(span_of(param, span), ""));
+                }
+
+                params.push(to_string(param));
+            }
+
+            rest = &rest[close + 1..];
+        }
+
+        Ok((name, params, trim(rest)))
+    }
+
+    /// Preprocesses a file that has been added to `sources`.
+    /// Included files are added to `sources` as they are found.
+    pub fn run(&mut self, sources: &mut SourceMap, file: FileId) -> Result<Expansion, XBFError> {
+        let mut out: Text = Vec::new();
+        let mut diags: Vec<Diagnostic> = Vec::new();
+
+        let path = fs::canonicalize(sources.get(file).get_name()).ok();
+
+        self.process(sources, file, path, &mut out, &mut diags);
+
+        if !diags.is_empty() {
+            return Err(XBFError::from(diags));
+        }
+
+        Ok(Expansion {
+            text: out.iter().map(|(ch, _)| *ch).collect(),
+            origins: out.iter().map(|(_, span)| *span).collect(),
+        })
+    }
+
+    /// Finds and reads the file named by an '#include' directive in `file`.
+    /// Returns `None` if the file was already included or can't be included.
+    fn include(
+        &mut self,
+        sources: &mut SourceMap,
+        file: FileId,
+        target: &[Spanned],
+        span: Span,
+        diags: &mut Vec<Diagnostic>,
+    ) -> Option<(FileId, PathBuf)> {
+        let target_str = to_string(target);
+
+        let (name, local) = match target_str.as_bytes() {
+            [b'"', name @ .., b'"'] => (name, true),
+            [b'<', name @ .., b'>'] => (name, false),
+            _ => {
+                diags.push(
+                    Diagnostic::error("expected a path after '#include'")
+                        .with_span(span_of(target, span), "")
+                        .with_note("write the path as \"path\" or <path>"),
+                );
+                return None;
+            }
+        };
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let mut dirs: Vec<PathBuf> = Vec::new();
+
+        if local {
+            let parent = Path::new(sources.get(file).get_name()).parent();
+            dirs.push(parent.unwrap_or(Path::new("")).to_path_buf());
+        }
+
+        for dir in &self.include_dirs {
+            if !dirs.contains(dir) {
+                dirs.push(dir.clone());
+            }
+        }
+
+        let found = match dirs.iter().map(|d| d.join(&name)).find(|p| p.is_file()) {
+            Some(found) => found,
+            None => {
+                let searched: Vec<String> = dirs
+                    .iter()
+                    .map(|d| match d.to_str() {
+                        Some("") => ".".to_string(),
+                        _ => d.display().to_string(),
+                    })
+                    .collect();
+
+                diags.push(
+                    Diagnostic::error(&format!("cannot find included file '{}'", name))
+                        .with_span(span, "")
+                        .with_note(&format!("searched in: {}", searched.join(", "))),
+                );
+                return None;
+            }
+        };
+
+        let canonical = fs::canonicalize(&found).unwrap_or(found.clone());
+
+        if self.stack.contains(&canonical) {
+            diags.push(
+                Diagnostic::error(&format!(
+                    "include cycle: '{}' is already being included",
+                    name
+                ))
+                .with_span(span, "this include would start over"),
+            );
+            return None;
+        }
+
+        if self.included.contains(&canonical) {
+            return None;
+        }
+
+        match fs::read(&found) {
+            Ok(content) => {
+                match sources.add_included(&found.display().to_string(), content, span) {
+                    Ok(id) => Some((id, canonical)),
+                    Err(diag) => {
+                        diags.push(diag.with_span(span, "included here"));
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                diags.push(
+                    Diagnostic::error(&format!("cannot read included file '{}': {}", name, e))
+                        .with_span(span, ""),
+                );
+                None
+            }
+        }
+    }
+
+    fn process(
+        &mut self,
+        sources: &mut SourceMap,
+        file: FileId,
+        path: Option<PathBuf>,
+        out: &mut Text,
+        diags: &mut Vec<Diagnostic>,
+    ) {
+        if let Some(path) = &path {
+            self.stack.push(path.clone());
+        }
+
+        let text = spanned(file, sources.get(file).get_text());
+
+        let mut conds: Vec<Cond> = Vec::new();
+        // The '#macro' whose lines are being collected.
+        let mut collecting: Option<(String, Macro)> = None;
+
+        for line in text.split_inclusive(|(ch, _)| *ch == b'\n') {
+            let trimmed = trim(line);
+            let span = span_of(trimmed, line[0].1);
+
+            let (directive, rest) = match trimmed.first() {
+                Some((b'#', _)) => {
+                    let (word, rest) = split_ident(&trimmed[1..]);
+                    (format!("#{}", word), rest)
+                }
+                _ => (String::new(), trimmed),
+            };
+
+            if let Some((name, mut m)) = collecting.take() {
+                if directive == "#endmacro" {
+                    self.macros.insert(name, m);
+                } else {
+                    m.body.extend_from_slice(line);
+                    collecting = Some((name, m));
+                }
+                continue;
+            }
+
+            let active = conds.iter().all(|c| c.keep());
+
+            match directive.as_str() {
+                "#ifdef" | "#ifndef" => {
+                    let name = to_string(trim(rest));
+
+                    conds.push(Cond {
+                        span,
+                        parent: active,
+                        taken: self.is_defined(&name) == (directive == "#ifdef"),
+                        in_else: false,
+                    });
+                }
+                "#else" => match conds.last_mut() {
+                    Some(c) if !c.in_else => c.in_else = true,
+                    _ => diags.push(
+                        Diagnostic::error("'#else' outside of an '#ifdef' section")
+                            .with_span(span, ""),
+                    ),
+                },
+                "#endif" => {
+                    if conds.pop().is_none() {
+                        diags.push(
+                            Diagnostic::error("'#endif' outside of an '#ifdef' section")
+                                .with_span(span, ""),
+                        );
+                    }
+                }
+                _ if !active => (),
+                "#define" | "#macro" => match self.parse_header(rest, span) {
+                    Ok((name, params, body)) => {
+                        let m = Macro {
+                            params,
+                            body: body.to_vec(),
+                            span,
+                        };
+
+                        if directive == "#define" {
+                            self.macros.insert(name, m);
+                        } else if !body.is_empty() {
+                            diags.push(
+                                Diagnostic::error("unexpected text after '#macro'")
+                                    .with_span(span_of(body, span), "")
+                                    .with_note("the body of a '#macro' starts on the next line"),
+                            );
+                        } else {
+                            collecting = Some((name, m));
+                        }
+                    }
+                    Err(diag) => diags.push(diag),
+                },
+                "#undef" => {
+                    let name = to_string(trim(rest));
+                    self.macros.remove(&name);
+                    self.defines.remove(&name);
+                }
+                "#include" => {
+                    if let Some((id, path)) = self.include(sources, file, trim(rest), span, diags) {
+                        self.process(sources, id, Some(path), out, diags);
+                    }
+                }
+                "#endmacro" => diags
+                    .push(Diagnostic::error("'#endmacro' without a '#macro'").with_span(span, "")),
+                _ => match self.expand(line, &HashMap::new(), 0) {
+                    Ok(expanded) => out.extend(expanded),
+                    Err(diag) => diags.push(diag),
+                },
+            }
+        }
+
+        if let Some((_, m)) = collecting {
+            diags.push(
+                Diagnostic::error("unclosed '#macro'")
+                    .with_span(m.span, "expected an '#endmacro' after this"),
+            );
+        }
+
+        for c in conds {
+            diags.push(
+                Diagnostic::error("unclosed conditional section")
+                    .with_span(c.span, "expected an '#endif' after this"),
+            );
+        }
+
+        if let Some(path) = path {
+            self.stack.pop();
+            self.included.insert(path);
+        }
+    }
+
+    /// Replaces every `{...}` in `text`.
+    fn expand(
+        &self,
+        text: &[Spanned],
+        scope: &HashMap<String, Text>,
+        depth: usize,
+    ) -> Result<Text, Diagnostic> {
+        let mut out = Vec::new();
+        let mut i = 0;
+
+        while i < text.len() {
+            if text[i].0 != b'{' {
+                out.push(text[i]);
+                i += 1;
+                continue;
+            }
+
+            let close = match matching_brace(text, i) {
+                Some(close) => close,
+                None => {
+                    return Err(Diagnostic::error("unclosed '{'").with_span(text[i].1, ""));
+                }
+            };
+
+            let span = span_of(&text[i..=close], text[i].1);
+
+            out.extend(self.eval(&text[i + 1..close], span, scope, depth)?);
+            i = close + 1;
+        }
+
+        Ok(out)
+    }
+
+    /// Evaluates the inside of a `{...}`.
+    fn eval(
+        &self,
+        inner: &[Spanned],
+        span: Span,
+        scope: &HashMap<String, Text>,
+        depth: usize,
+    ) -> Result<Text, Diagnostic> {
+        if depth > MAX_DEPTH {
+            return Err(Diagnostic::error("macros expand too deeply")
+                .with_span(span, "while expanding this")
+                .with_note("is a macro expanding itself?"));
+        }
+
+        let star = match rfind_top(inner, b'*') {
+            Some(star) => star,
+            None => return self.invoke(trim(inner), span, scope, depth),
+        };
+
+        let code = trim(&inner[..star]);
+        let count = trim(&inner[star + 1..]);
+
+        let count_str = match scope.get(&to_string(count)) {
+            Some(value) => to_string(trim(value)),
+            None => to_string(count),
+        };
+
+        let n: usize = match count_str.parse() {
+            Ok(n) => n,
+            Err(_) => {
+                return Err(Diagnostic::error("expected a repeat count")
+                    .with_span(span_of(count, span), "this is not a number"))
+            }
+        };
+
+        let starts_with_ident = code
+            .first()
+            .is_some_and(|(ch, _)| ch.is_ascii_alphabetic() || *ch == b'_');
+
+        let code = if starts_with_ident {
+            self.invoke(code, span, scope, depth)?
+        } else {
+            self.expand(code, scope, depth + 1)?
+        };
+
+        match code.len().checked_mul(n) {
+            Some(len) if len <= MAX_REPEAT_LEN => Ok(code.repeat(n)),
+            _ => Err(Diagnostic::error("repeat is too large")
+                .with_span(span_of(count, span), "this many times")
+                .with_note(&format!(
+                    "a repeat can expand to at most {} bytes",
+                    MAX_REPEAT_LEN
+                ))),
+        }
+    }
+
+    /// Expands `NAME` or `NAME(args)`, which is either a parameter in `scope` or a macro.
+    fn invoke(
+        &self,
+        call: &[Spanned],
+        span: Span,
+        scope: &HashMap<String, Text>,
+        depth: usize,
+    ) -> Result<Text, Diagnostic> {
+        let (name, rest) = split_ident(call);
+        let rest = trim(rest);
+
+        let args = match rest {
+            [] => None,
+            [(b'(', _), inside @ .., (b')', _)] => {
+                let mut args = Vec::new();
+
+                if !trim(inside).is_empty() {
+                    for arg in split_top(inside, b',') {
+                        args.push(self.expand(trim(arg), scope, depth + 1)?);
+                    }
+                }
+
+                Some(args)
+            }
+            _ => None,
+        };
+
+        if !is_ident(&name) || (args.is_none() && !rest.is_empty()) {
+            return Err(Diagnostic::error("invalid macro expression")
+                .with_span(span, "")
+                .with_note(
+                    "expected a macro like '{name}' or '{name(a, b)}', or a repeat like '{+ * 3}'",
+                ));
+        }
+
+        if args.is_none() {
+            if let Some(value) = scope.get(&name) {
+                return Ok(value.clone());
+            }
+        }
+
+        let m = match self.macros.get(&name) {
+            Some(m) => m,
+            None => {
+                return Err(Diagnostic::error(&format!("unknown macro '{}'", name))
+                    .with_span(span, "used here"))
+            }
+        };
+
+        let args = args.unwrap_or_default();
+
+        if args.len() != m.params.len() {
+            return Err(Diagnostic::error(&format!(
+                "macro '{}' takes {} argument(s) but {} were given",
+                name,
+                m.params.len(),
+                args.len()
+            ))
+            .with_span(span, "")
+            .with_label(m.span, "defined here"));
+        }
+
+        let scope = m.params.iter().cloned().zip(args).collect();
+
+        self.expand(&m.body, &scope, depth + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Renderer;
+
+    /// Preprocesses `code`, returning the expansion or the rendered diagnostics.
+    fn preprocess(code: &str) -> Result<(String, Vec<Span>), String> {
+        preprocess_as("<test>", code, &[])
+    }
+
+    fn preprocess_as(
+        name: &str,
+        code: &str,
+        include_dirs: &[String],
+    ) -> Result<(String, Vec<Span>), String> {
+        let mut sources = SourceMap::new();
+        let id = sources.add(name, code.as_bytes().to_vec()).unwrap();
+
+        match Preprocessor::new(&[], include_dirs).run(&mut sources, id) {
+            Ok(expansion) => Ok((
+                String::from_utf8(expansion.text).unwrap(),
+                expansion.origins,
+            )),
+            Err(e) => Err(e
+                .get_diagnostics()
+                .iter()
+                .map(|d| Renderer::new(&sources, false).render(d).unwrap())
+                .collect()),
+        }
+    }
+
+    fn expand(code: &str) -> String {
+        match preprocess(code) {
+            Ok((text, _)) => text,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn expands_macros() {
+        assert_eq!(expand("#define TWO ++\n{TWO}>{TWO}\n"), "++>++\n");
+        assert_eq!(
+            expand("#macro CLEAR\n[-]\n#endmacro\n>{CLEAR}<\n"),
+            ">[-]\n<\n"
+        );
+        assert_eq!(
+            expand("#define TWO ++\n#undef TWO\n#ifdef TWO\n{TWO}\n#endif\n"),
+            ""
+        );
+
+        let err = preprocess("{NOPE}\n").unwrap_err();
+        assert!(err.contains("error: unknown macro 'NOPE'"), "{}", err);
+    }
+
+    #[test]
+    fn expands_parameters() {
+        assert_eq!(
+            expand("#define MOVE(a, b) [-{a}+{b}]\n{MOVE(>, <)}\n"),
+            "[->+<]\n"
+        );
+        assert_eq!(expand("#define ADD(n) {+ * n}\n{ADD(3)}.\n"), "+++.\n");
+        // Arguments can hold other macros and commas nested in braces.
+        assert_eq!(
+            expand("#define PAIR(a, b) {a}{b}\n#define R >\n{PAIR({R}, {PAIR(-, -)})}\n"),
+            ">--\n"
+        );
+
+        let err = preprocess("#define ONE(a) {a}\n{ONE(+, -)}\n").unwrap_err();
+        assert!(
+            err.contains("error: macro 'ONE' takes 1 argument(s) but 2 were given"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn expands_repeats() {
+        assert_eq!(expand("{+ * 3}{> * 0}.\n"), "+++.\n");
+        assert_eq!(expand("{{+> * 2} * 2}\n"), "+>+>+>+>\n");
+        assert_eq!(expand("#define BUMP +>\n{BUMP * 2}\n"), "+>+>\n");
+
+        let err = preprocess("{+ * many}\n").unwrap_err();
+        assert!(err.contains("error: expected a repeat count"), "{}", err);
+
+        let err = preprocess("#define SELF {SELF}\n{SELF}\n").unwrap_err();
+        assert!(err.contains("error: macros expand too deeply"), "{}", err);
+    }
+
+    #[test]
+    fn keeps_conditional_sections() {
+        let code = "#ifdef FAST\nfast\n#else\nslow\n#endif\n#ifndef FAST\n!\n#endif\n";
+
+        assert_eq!(expand(code), "slow\n!\n");
+        assert_eq!(expand(&format!("#define FAST\n{}", code)), "fast\n");
+
+        // Sections nested in a skipped section are skipped too.
+        assert_eq!(
+            expand("#ifdef A\n#ifndef B\nx\n#else\ny\n#endif\n#endif\nz\n"),
+            "z\n"
+        );
+
+        let err = preprocess("#ifdef A\n").unwrap_err();
+        assert!(
+            err.contains("error: unclosed conditional section"),
+            "{}",
+            err
+        );
+
+        let err = preprocess("#else\n").unwrap_err();
+        assert!(err.contains("error: '#else' outside"), "{}", err);
+    }
+
+    #[test]
+    fn spans_point_into_the_source() {
+        let (text, origins) = preprocess("#define INC +\n  >{INC}<\n").unwrap();
+
+        assert_eq!(text, "  >+<\n");
+
+        let at: Vec<(u32, u32)> = origins.iter().map(|s| (s.ln, s.col)).collect();
+        // The '+' comes from the body of the macro on line 1.
+        assert_eq!(at, [(2, 1), (2, 2), (2, 3), (1, 13), (2, 9), (2, 10)]);
+
+        // Errors inside a macro body point at the body, not at where it is used.
+        let err = preprocess("#define BAD {+ * x}\n\n{BAD}\n").unwrap_err();
+        assert!(err.contains("--> <test>:1:18"), "{}", err);
+    }
+
+    #[test]
+    fn huge_repeat_is_an_error() {
+        let err = preprocess("{+ * 99999999999999}\n").unwrap_err();
+
+        assert!(err.contains("error: repeat is too large"), "{}", err);
+        assert!(err.contains("--> <test>:1:6"), "{}", err);
+
+        let err = preprocess("{{+ * 4096} * 4096}\n").unwrap_err();
+        assert!(err.contains("error: repeat is too large"), "{}", err);
+
+        assert_eq!(preprocess("{+ * 4096}\n").unwrap().0.len(), 4097);
+    }
+
+    /// Writes `files` into a fresh directory for one test, returning its path.
+    fn fixture(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xbf-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+
+        for (name, text) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+
+        dir
+    }
+
+    /// Preprocesses `main.b` in `dir`.
+    fn include(dir: &Path, include_dirs: &[&Path]) -> Result<String, String> {
+        let main = dir.join("main.b");
+        let code = fs::read_to_string(&main).unwrap();
+        let include_dirs: Vec<String> = include_dirs
+            .iter()
+            .map(|d| d.display().to_string())
+            .collect();
+
+        preprocess_as(&main.display().to_string(), &code, &include_dirs).map(|(text, _)| text)
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let dir = fixture(
+            "cycle",
+            &[
+                ("main.b", "#include \"a.b\"\n"),
+                ("a.b", "+\n#include \"b.b\"\n"),
+                ("b.b", "-\n#include \"a.b\"\n"),
+            ],
+        );
+
+        let err = include(&dir, &[]).unwrap_err();
+        assert!(
+            err.contains("error: include cycle: 'a.b' is already being included"),
+            "{}",
+            err
+        );
+        assert!(err.contains("b.b:2:1"), "{}", err);
+    }
+
+    #[test]
+    fn files_are_included_once() {
+        let dir = fixture(
+            "once",
+            &[
+                (
+                    "main.b",
+                    "#include \"lib.b\"\n.\n#include \"lib.b\"\n#include \"sub/x.b\"\n",
+                ),
+                ("lib.b", "#define TWO ++\n>\n"),
+                ("sub/x.b", "#include \"../lib.b\"\n{TWO}\n"),
+            ],
+        );
+
+        assert_eq!(include(&dir, &[]).unwrap(), ">\n.\n++\n");
+    }
+
+    #[test]
+    fn includes_are_found_in_include_dirs() {
+        let dir = fixture(
+            "dirs",
+            &[
+                ("main.b", "#include <lib.b>\n#include \"near.b\"\n"),
+                ("near.b", "<\n"),
+                ("lib/lib.b", ">\n"),
+                ("lib/near.b", "wrong\n"),
+            ],
+        );
+
+        let err = include(&dir, &[]).unwrap_err();
+        assert!(
+            err.contains("error: cannot find included file 'lib.b'"),
+            "{}",
+            err
+        );
+
+        // Quoted paths look next to the including file before the include directories.
+        assert_eq!(include(&dir, &[&dir.join("lib")]).unwrap(), ">\n<\n");
+    }
+
+    #[test]
+    fn errors_show_the_include_chain() {
+        let dir = fixture(
+            "chain",
+            &[
+                ("main.b", "+\n#include \"mid.b\"\n"),
+                ("mid.b", "\n#include \"bad.b\"\n"),
+                ("bad.b", "{NOPE}\n"),
+            ],
+        );
+
+        let err = include(&dir, &[]).unwrap_err();
+        let mid = format!("included from {}:2:1", dir.join("mid.b").display());
+        let main = format!("included from {}:2:1", dir.join("main.b").display());
+
+        assert!(err.contains("error: unknown macro 'NOPE'"), "{}", err);
+        assert!(err.contains("bad.b:1:1"), "{}", err);
+        assert!(err.contains(&mid) && err.contains(&main), "{}", err);
+        assert!(err.find(&mid) < err.find(&main), "{}", err);
+    }
+}
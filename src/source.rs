@@ -0,0 +1,167 @@
+use std::borrow::Cow;
+
+use crate::{
+    common::{FileId, Span},
+    diagnostic::Diagnostic,
+};
+
+/// The largest source file, so every offset, line and column in it fits a span.
+pub const MAX_FILE_LEN: usize = u32::MAX as usize;
+
+pub struct SourceFile {
+    name: String,
+    text: Vec<u8>,
+    utf8: bool,
+    line_starts: Vec<usize>,
+    included_from: Option<Span>,
+}
+
+impl SourceFile {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// Returns the '#include' directive that brought this file in, if any.
+    pub fn get_included_from(&self) -> Option<Span> {
+        self.included_from
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    /// Decodes bytes of this file for display.
+    /// Files that aren't valid UTF-8 are read as Latin-1, so every byte is one column,
+    /// which matches how the lexer counts columns in them.
+    pub fn decode<'a>(&self, bytes: &'a [u8]) -> Cow<'a, str> {
+        if self.utf8 {
+            String::from_utf8_lossy(bytes)
+        } else {
+            Cow::Owned(bytes.iter().map(|b| *b as char).collect())
+        }
+    }
+
+    fn line_bytes(&self, ln: usize) -> &[u8] {
+        let start = match self.line_starts.get(ln - 1) {
+            Some(start) => *start,
+            None => return &[],
+        };
+
+        let end = self
+            .line_starts
+            .get(ln)
+            .map(|end| end - 1)
+            .unwrap_or(self.text.len());
+
+        let line = &self.text[start..end];
+
+        line.strip_suffix(b"\r").unwrap_or(line)
+    }
+
+    /// Returns the text of the 1-based line `ln`, without its line ending.
+    pub fn get_line(&self, ln: usize) -> Cow<'_, str> {
+        self.decode(self.line_bytes(ln))
+    }
+}
+
+/// Keeps every source file passed to the compiler,
+/// so that a span can be traced back to the file its bytes came from.
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap { files: Vec::new() }
+    }
+
+    /// Registers a file and returns the id its tokens should be lexed with.
+    /// Fails if the file is larger than `MAX_FILE_LEN`.
+    pub fn add(&mut self, name: &str, text: Vec<u8>) -> Result<FileId, Diagnostic> {
+        if text.len() > MAX_FILE_LEN {
+            return Err(Diagnostic::error(&format!("'{}' is too large", name))
+                .with_note("source files must be smaller than 4 GiB"));
+        }
+
+        let line_starts = std::iter::once(0)
+            .chain(
+                text.iter()
+                    .enumerate()
+                    .filter(|(_, b)| **b == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .collect();
+
+        self.files.push(SourceFile {
+            name: name.to_string(),
+            utf8: std::str::from_utf8(&text).is_ok(),
+            text,
+            line_starts,
+            included_from: None,
+        });
+
+        Ok((self.files.len() - 1) as FileId)
+    }
+
+    /// Registers a file brought in by the '#include' directive at `from`.
+    pub fn add_included(
+        &mut self,
+        name: &str,
+        text: Vec<u8>,
+        from: Span,
+    ) -> Result<FileId, Diagnostic> {
+        let id = self.add(name, text)?;
+        self.files[id as usize].included_from = Some(from);
+        Ok(id)
+    }
+
+    pub fn get(&self, file: FileId) -> &SourceFile {
+        &self.files[file as usize]
+    }
+
+    /// Returns an empty span at the 1-based line `ln` and column `col` of a file.
+    pub fn span_at(&self, file: FileId, ln: usize, col: usize) -> Span {
+        let source = self.get(file);
+        let line = source.line_bytes(ln);
+
+        let offset = if source.utf8 {
+            let line = String::from_utf8_lossy(line);
+            line.char_indices()
+                .nth(col - 1)
+                .map_or(line.len(), |(i, _)| i)
+        } else {
+            (col - 1).min(line.len())
+        };
+        let start = source.line_starts[ln - 1] + offset;
+
+        Span::new(file, start, start, ln, col)
+    }
+
+    /// Formats a span as `file:line:col`.
+    pub fn location(&self, span: Span) -> String {
+        format!(
+            "{}:{}:{}",
+            self.get(span.file).get_name(),
+            span.ln,
+            span.col
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_files_spans_cant_hold() {
+        let mut sources = SourceMap::new();
+
+        // The pages are never touched, so this doesn't take 4 GiB of memory.
+        assert!(sources.add("big", vec![0; MAX_FILE_LEN + 1]).is_err());
+        assert_eq!(sources.add("small", vec![b'+'; 16]).ok(), Some(0));
+    }
+}
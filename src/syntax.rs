@@ -0,0 +1,294 @@
+use crate::{
+    common::{FileId, Span, XBFError},
+    diagnostic::Diagnostic,
+    preprocessor::Expansion,
+    source::SourceMap,
+};
+
+/// The characters a mapping can produce: the core commands and those of every dialect.
+/// '#' starts a comment in a mapping file, so the debug command can't be mapped.
+const COMMANDS: &[u8] = b"+-<>[].,@$!}{~^&|():Y";
+
+const OOK: [(&str, u8); 8] = [
+    ("Ook. Ook?", b'>'),
+    ("Ook? Ook.", b'<'),
+    ("Ook. Ook.", b'+'),
+    ("Ook! Ook!", b'-'),
+    ("Ook! Ook.", b'.'),
+    ("Ook. Ook!", b','),
+    ("Ook! Ook?", b'['),
+    ("Ook? Ook!", b']'),
+];
+
+const BLUB: [(&str, u8); 8] = [
+    ("Blub. Blub?", b'>'),
+    ("Blub? Blub.", b'<'),
+    ("Blub. Blub.", b'+'),
+    ("Blub! Blub!", b'-'),
+    ("Blub! Blub.", b'.'),
+    ("Blub. Blub!", b','),
+    ("Blub! Blub?", b'['),
+    ("Blub? Blub!", b']'),
+];
+
+/// Joins the words of `text` with single spaces, which stand for any run of whitespace.
+fn normalize(text: &[u8]) -> Vec<u8> {
+    text.split(|ch| ch.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&[u8]>>()
+        .join(&b' ')
+}
+
+fn is_word(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || ch == b'_'
+}
+
+/// Returns the length of the match of `key` at `text[start..]`, if it matches.
+/// Keys that start or end in a word only match there if that word isn't part of a longer one,
+/// so that a key like `in` doesn't match inside `print`.
+fn match_len(key: &[u8], text: &[u8], start: usize) -> Option<usize> {
+    let before = start.checked_sub(1).map(|i| text[i]);
+
+    if key.first().is_some_and(|ch| is_word(*ch)) && before.is_some_and(is_word) {
+        return None;
+    }
+
+    let text = &text[start..];
+    let mut len = 0;
+
+    for ch in key {
+        if *ch == b' ' {
+            let ws = text[len..]
+                .iter()
+                .take_while(|ch| ch.is_ascii_whitespace())
+                .count();
+
+            if ws == 0 {
+                return None;
+            }
+
+            len += ws;
+        } else if text.get(len) == Some(ch) {
+            len += 1;
+        } else {
+            return None;
+        }
+    }
+
+    if key.last().is_some_and(|ch| is_word(*ch)) && text.get(len).is_some_and(|ch| is_word(*ch)) {
+        return None;
+    }
+
+    Some(len)
+}
+
+/// A surface syntax that spells each command with a string instead of a single character.
+pub struct Syntax {
+    keys: Vec<(Vec<u8>, u8)>,
+}
+
+impl Syntax {
+    fn builtin(keys: &[(&str, u8)]) -> Syntax {
+        Syntax {
+            keys: keys
+                .iter()
+                .map(|(key, ch)| (key.as_bytes().to_vec(), *ch))
+                .collect(),
+        }
+    }
+
+    /// Returns a built-in syntax, 'ook' or 'blub'.
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "ook" => Some(Syntax::builtin(&OOK)),
+            "blub" => Some(Syntax::builtin(&BLUB)),
+            _ => None,
+        }
+    }
+
+    /// Reads a mapping file, where each line is a command character followed by its string:
+    ///
+    /// ```text
+    /// # Lines starting with '#' are comments.
+    /// + more
+    /// - less
+    /// [ while not zero
+    /// ```
+    ///
+    /// Spaces in a string match any run of whitespace in the source.
+    /// The debug command, '#', can't be mapped, since its line would be a comment.
+    pub fn parse(sources: &SourceMap, file: FileId) -> Result<Syntax, XBFError> {
+        let source = sources.get(file);
+
+        let mut keys: Vec<(Vec<u8>, u8)> = Vec::new();
+        let mut key_spans: Vec<Span> = Vec::new();
+        let mut diags: Vec<Diagnostic> = Vec::new();
+
+        let mut offset = 0;
+
+        for (i, line) in source.get_text().split(|ch| *ch == b'\n').enumerate() {
+            let line_start = offset;
+            offset += line.len() + 1;
+
+            let indent = line
+                .iter()
+                .take_while(|ch| ch.is_ascii_whitespace())
+                .count();
+            let rest = &line[indent..];
+
+            let ch = match rest.first() {
+                None | Some(b'#') => continue,
+                Some(ch) => *ch,
+            };
+
+            let start = line_start + indent;
+            let span = Span::new(file, start, start + 1, i + 1, indent + 1);
+
+            if !COMMANDS.contains(&ch) {
+                diags.push(
+                    Diagnostic::error("unknown command in syntax mapping")
+                        .with_span(span, "this should be a command character")
+                        .with_note(&format!(
+                            "the command characters are '{}'",
+                            String::from_utf8_lossy(COMMANDS)
+                        )),
+                );
+                continue;
+            }
+
+            let key = normalize(&rest[1..]);
+            let key_span = Span::new(file, start, line_start + line.len(), i + 1, indent + 1);
+
+            if key.is_empty() {
+                diags.push(
+                    Diagnostic::error(&format!("no string given for '{}'", ch as char))
+                        .with_span(span, "expected a string after this"),
+                );
+                continue;
+            }
+
+            if let Some(j) = keys.iter().position(|(k, _)| *k == key) {
+                diags.push(
+                    Diagnostic::error(&format!(
+                        "'{}' is mapped more than once",
+                        String::from_utf8_lossy(&key)
+                    ))
+                    .with_span(key_span, "mapped again here")
+                    .with_label(key_spans[j], "first mapped here"),
+                );
+                continue;
+            }
+
+            keys.push((key, ch));
+            key_spans.push(key_span);
+        }
+
+        if !diags.is_empty() {
+            return Err(XBFError::from(diags));
+        }
+
+        Ok(Syntax { keys })
+    }
+
+    /// Replaces every string of this syntax in `input` with its command character,
+    /// dropping everything else. Where strings overlap, the longest one wins.
+    pub fn translate(&self, input: &Expansion) -> Expansion {
+        let mut out = Expansion {
+            text: Vec::new(),
+            origins: Vec::new(),
+        };
+
+        let mut i = 0;
+
+        while i < input.text.len() {
+            let best = self
+                .keys
+                .iter()
+                .filter_map(|(key, ch)| match_len(key, &input.text, i).map(|len| (len, *ch)))
+                .max_by_key(|(len, _)| *len);
+
+            match best {
+                Some((len, ch)) => {
+                    out.text.push(ch);
+                    out.origins
+                        .push(input.origins[i].join(input.origins[i + len - 1]));
+
+                    i += len;
+                }
+                None => i += 1,
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(syntax: &Syntax, code: &str) -> String {
+        let out = syntax.translate(&Expansion::of_file(0, code.as_bytes()));
+        String::from_utf8(out.text).unwrap()
+    }
+
+    fn mapping(text: &str) -> Result<Syntax, String> {
+        let mut sources = SourceMap::new();
+        let id = sources.add("<mapping>", text.as_bytes().to_vec()).unwrap();
+
+        Syntax::parse(&sources, id).map_err(|e| format!("{:?}", e))
+    }
+
+    #[test]
+    fn builtin_tables() {
+        let ook = Syntax::from_name("ook").unwrap();
+        let code =
+            "Ook. Ook? Ook? Ook. Ook. Ook. Ook! Ook! Ook! Ook. Ook. Ook! Ook! Ook? Ook? Ook!";
+        assert_eq!(translate(&ook, code), "><+-.,[]");
+
+        let blub = Syntax::from_name("blub").unwrap();
+        let code = "Blub. Blub? Blub? Blub. Blub. Blub. Blub! Blub! Blub! Blub. Blub. Blub! \
+                    Blub! Blub? Blub? Blub!";
+        assert_eq!(translate(&blub, code), "><+-.,[]");
+
+        assert!(Syntax::from_name("moo").is_none());
+    }
+
+    #[test]
+    fn keys_can_touch() {
+        let ook = Syntax::from_name("ook").unwrap();
+
+        // The words of a key can be split by any whitespace, and keys needn't be split at all.
+        assert_eq!(translate(&ook, "Ook.\n  Ook?Ook. Ook.Ook! Ook!"), ">+-");
+        // The words of a key can't touch each other.
+        assert_eq!(translate(&ook, "Ook.Ook?"), "");
+    }
+
+    #[test]
+    fn keys_match_whole_words() {
+        let Ok(syntax) = mapping("+ in\n- out\n. say it\n") else {
+            panic!("the mapping doesn't parse");
+        };
+
+        assert_eq!(translate(&syntax, "print in; out(put) shout"), "+-");
+        assert_eq!(translate(&syntax, "say it, say items"), ".");
+    }
+
+    #[test]
+    fn mappings_take_every_command() {
+        let Ok(syntax) = mapping("# comment\nY fork\n: call\n( def\n) fed\n") else {
+            panic!("the mapping doesn't parse");
+        };
+        assert_eq!(translate(&syntax, "def call fed fork"), "(:)Y");
+
+        let Err(err) = mapping("% percent\n") else {
+            panic!("'%' was mapped");
+        };
+        assert!(err.contains("unknown command in syntax mapping"), "{}", err);
+
+        let Err(err) = mapping("+ up\n- up\n") else {
+            panic!("'up' was mapped twice");
+        };
+        assert!(err.contains("'up' is mapped more than once"), "{}", err);
+    }
+}
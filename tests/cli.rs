@@ -0,0 +1,58 @@
+//! Runs the compiler binary for behavior that only shows at the command line.
+
+use std::{fs, path::PathBuf, process::Command};
+
+fn xbf() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_xbf"))
+}
+
+/// Writes `text` to a file of its own for one test, returning its path.
+fn source(test: &str, text: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("xbf-cli-{}-{}", std::process::id(), test));
+    fs::create_dir_all(&dir).unwrap();
+
+    let path = dir.join("main.b");
+    fs::write(&path, text).unwrap();
+    path
+}
+
+#[test]
+fn fmt_check_fails_on_unformatted_files() {
+    let path = source("fmt-check", "+[ ->+< ]  add\n");
+
+    let out = xbf().args(["fmt", "--check"]).arg(&path).output().unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("is not formatted"));
+    // Checking never writes.
+    assert_eq!(fs::read_to_string(&path).unwrap(), "+[ ->+< ]  add\n");
+
+    let out = xbf().arg("fmt").arg(&path).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "+[->+<] add\n");
+
+    let out = xbf().args(["fmt", "--check"]).arg(&path).output().unwrap();
+    assert!(out.status.success());
+}
+
+#[test]
+fn lint_flags_toggle_lints() {
+    let path = source("lint", "[comment] <\n");
+    let (dead, underflow) = ("this loop never runs", "moves before the start of the tape");
+    let lint = |flags: &[&str]| {
+        let out = xbf().arg("lint").args(flags).arg(&path).output().unwrap();
+        String::from_utf8_lossy(&out.stderr).into_owned()
+    };
+
+    let out = lint(&[]);
+    assert!(!out.contains(dead), "{}", out);
+    assert!(out.contains(underflow), "{}", out);
+
+    let out = lint(&["-W", "dead-loop", "-A", "tape-underflow"]);
+    assert!(out.contains(dead), "{}", out);
+    assert!(!out.contains(underflow), "{}", out);
+
+    // Naming a lint takes precedence over 'all'.
+    let out = lint(&["-A", "all", "-W", "tape-underflow"]);
+    assert!(!out.contains(dead), "{}", out);
+    assert!(out.contains(underflow), "{}", out);
+}